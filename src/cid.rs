@@ -0,0 +1,84 @@
+use crate::register_bits;
+
+/// The Card Identification register, which identifies the card model and the individual card.
+#[derive(Copy, Clone)]
+pub struct Cid(pub(crate) [u32; 4]);
+
+impl core::fmt::Debug for Cid {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        let oem_id = self.oem_id();
+        let product_name = self.product_name();
+        let (major, minor) = self.product_revision();
+        let (year, month) = self.manufacturing_date();
+        write!(f, "Cid(")?;
+        write!(f, "manufacturer_id={:#04x}, ", self.manufacturer_id())?;
+        write!(f, "oem_id={:?}, ", core::str::from_utf8(&oem_id))?;
+        write!(
+            f,
+            "product_name={:?}, ",
+            core::str::from_utf8(&product_name)
+        )?;
+        write!(f, "product_revision={}.{}, ", major, minor)?;
+        write!(f, "serial_number={:#010x}, ", self.serial_number())?;
+        write!(f, "manufacturing_date={}-{:02})", year, month)?;
+        Ok(())
+    }
+}
+
+impl Cid {
+    fn bits(&self, high: usize, low: usize) -> u32 {
+        register_bits(&self.0, high, low)
+    }
+
+    /// The manufacturer ID assigned by the SD Card Association (MID).
+    pub fn manufacturer_id(&self) -> u8 {
+        self.bits(127, 120) as u8
+    }
+
+    /// The OEM or application ID as two ASCII characters (OID).
+    pub fn oem_id(&self) -> [u8; 2] {
+        [self.bits(119, 112) as u8, self.bits(111, 104) as u8]
+    }
+
+    /// The product name as five ASCII characters (PNM).
+    pub fn product_name(&self) -> [u8; 5] {
+        let mut name = [0; 5];
+        for (i, c) in name.iter_mut().enumerate() {
+            let high = 103 - i * 8;
+            *c = self.bits(high, high - 7) as u8;
+        }
+
+        name
+    }
+
+    /// The product revision as a major and minor version number (PRV).
+    pub fn product_revision(&self) -> (u8, u8) {
+        (self.bits(63, 60) as u8, self.bits(59, 56) as u8)
+    }
+
+    /// The serial number of the individual card (PSN).
+    pub fn serial_number(&self) -> u32 {
+        self.bits(55, 24)
+    }
+
+    /// The year and month the card was manufactured (MDT).
+    pub fn manufacturing_date(&self) -> (u16, u8) {
+        (2000 + self.bits(19, 12) as u16, self.bits(11, 8) as u8)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decode() {
+        let cid = Cid([0x0353_4453, 0x5530_3847, 0x807a_5c1e, 0x3f00_eaf9]);
+        assert_eq!(cid.manufacturer_id(), 0x03);
+        assert_eq!(cid.oem_id(), *b"SD");
+        assert_eq!(cid.product_name(), *b"SU08G");
+        assert_eq!(cid.product_revision(), (8, 0));
+        assert_eq!(cid.serial_number(), 0x7a5c_1e3f);
+        assert_eq!(cid.manufacturing_date(), (2014, 10));
+    }
+}
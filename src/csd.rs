@@ -0,0 +1,275 @@
+use crate::{register_bits, BlockCount, Error, BLOCK_SIZE};
+
+/// Multipliers for the time and rate units in TAAC and TRAN_SPEED, times ten.
+const TIME_VALUES: [u32; 16] = [
+    0, 10, 12, 13, 15, 20, 25, 30, 35, 40, 45, 50, 55, 60, 70, 80,
+];
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum CsdVersion {
+    /// Standard capacity cards.
+    V1,
+    /// High and extended capacity cards.
+    V2,
+}
+
+/// The Card Specific Data register, which describes the capabilities of the card.
+#[derive(Copy, Clone)]
+pub struct Csd(pub(crate) [u32; 4]);
+
+impl core::fmt::Debug for Csd {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "Csd(")?;
+        write!(f, "version={:?}, ", self.version())?;
+        write!(f, "capacity={:x?}, ", self.capacity())?;
+        write!(f, "taac={:?}ns, ", self.taac())?;
+        write!(f, "nsac={:?}, ", self.nsac())?;
+        write!(f, "tran_speed={:?}Hz, ", self.tran_speed())?;
+        write!(f, "ccc={:#x}, ", self.ccc())?;
+        write!(f, "read_block_len={}, ", self.read_block_len())?;
+        write!(f, "write_block_len={}, ", self.write_block_len())?;
+        write!(f, "sector_size={}, ", self.sector_size())?;
+        write!(f, "r2w_factor={}, ", self.r2w_factor())?;
+        write!(f, "copy={:?}, ", self.copy())?;
+        write!(f, "perm_write_protect={:?}, ", self.perm_write_protect())?;
+        write!(f, "tmp_write_protect={:?})", self.tmp_write_protect())?;
+        Ok(())
+    }
+}
+
+impl Csd {
+    fn bits(&self, high: usize, low: usize) -> u32 {
+        register_bits(&self.0, high, low)
+    }
+
+    fn bit(&self, bit: usize) -> bool {
+        self.bits(bit, bit) != 0
+    }
+
+    /// The layout of the register, from the CSD_STRUCTURE field.
+    pub fn version(&self) -> Result<CsdVersion, Error> {
+        match self.bits(127, 126) {
+            0 => Ok(CsdVersion::V1),
+            1 => Ok(CsdVersion::V2),
+            _ => Err(Error::InvalidValue),
+        }
+    }
+
+    /// The asynchronous part of the data access time in nanoseconds (TAAC).
+    pub fn taac(&self) -> Result<u32, Error> {
+        let unit = 10u32.pow(self.bits(114, 112));
+        match TIME_VALUES[self.bits(118, 115) as usize] {
+            0 => Err(Error::InvalidValue),
+            value => Ok(unit * value / 10),
+        }
+    }
+
+    /// The clock dependent part of the data access time in clock cycles (NSAC).
+    pub fn nsac(&self) -> u32 {
+        self.bits(111, 104) * 100
+    }
+
+    /// The maximum data transfer rate in bits per second per data line (TRAN_SPEED).
+    pub fn tran_speed(&self) -> Result<u32, Error> {
+        let unit = match self.bits(98, 96) {
+            unit @ 0..=3 => 100_000 * 10u32.pow(unit),
+            _ => return Err(Error::InvalidValue),
+        };
+
+        match TIME_VALUES[self.bits(102, 99) as usize] {
+            0 => Err(Error::InvalidValue),
+            value => Ok(unit / 10 * value),
+        }
+    }
+
+    /// The command classes supported by the card as a bit mask (CCC).
+    pub fn ccc(&self) -> u16 {
+        self.bits(95, 84) as u16
+    }
+
+    /// The maximum read data block length in bytes (READ_BL_LEN).
+    pub fn read_block_len(&self) -> usize {
+        1 << self.bits(83, 80)
+    }
+
+    /// Partial blocks can be read (READ_BL_PARTIAL).
+    pub fn read_block_partial(&self) -> bool {
+        self.bit(79)
+    }
+
+    /// Written blocks may cross physical block boundaries (WRITE_BLK_MISALIGN).
+    pub fn write_block_misalign(&self) -> bool {
+        self.bit(78)
+    }
+
+    /// Read blocks may cross physical block boundaries (READ_BLK_MISALIGN).
+    pub fn read_block_misalign(&self) -> bool {
+        self.bit(77)
+    }
+
+    /// The card has a configurable driver stage (DSR_IMP).
+    pub fn dsr_implemented(&self) -> bool {
+        self.bit(76)
+    }
+
+    /// The raw device size field (C_SIZE). Its meaning depends on the version.
+    pub fn c_size(&self) -> Result<u32, Error> {
+        match self.version()? {
+            CsdVersion::V1 => Ok(self.bits(73, 62)),
+            CsdVersion::V2 => Ok(self.bits(69, 48)),
+        }
+    }
+
+    /// The device size multiplier of version 1 registers (C_SIZE_MULT).
+    pub fn c_size_mult(&self) -> Result<u32, Error> {
+        match self.version()? {
+            CsdVersion::V1 => Ok(self.bits(49, 47)),
+            CsdVersion::V2 => Err(Error::InvalidValue),
+        }
+    }
+
+    /// The card size in blocks of `BLOCK_SIZE` bytes.
+    pub fn capacity(&self) -> Result<BlockCount, Error> {
+        let c_size = self.c_size()? + 1;
+        match self.version()? {
+            CsdVersion::V1 => {
+                let mult = self.c_size_mult()? + 2;
+                let block_len = self.bits(83, 80);
+                let shift = (mult + block_len)
+                    .checked_sub(BLOCK_SIZE.trailing_zeros())
+                    .ok_or(Error::InvalidValue)?;
+                Ok(c_size << shift)
+            }
+            CsdVersion::V2 => Ok(c_size << 10),
+        }
+    }
+
+    /// Single blocks can be erased, rather than only whole sectors (ERASE_BLK_EN).
+    pub fn erase_block_enable(&self) -> bool {
+        self.bit(46)
+    }
+
+    /// The size of an erasable sector in write blocks (SECTOR_SIZE).
+    pub fn sector_size(&self) -> u32 {
+        self.bits(45, 39) + 1
+    }
+
+    /// The size of a write protect group in erase sectors (WP_GRP_SIZE).
+    pub fn wp_group_size(&self) -> u32 {
+        self.bits(38, 32) + 1
+    }
+
+    /// Group write protection is supported (WP_GRP_ENABLE).
+    pub fn wp_group_enable(&self) -> bool {
+        self.bit(31)
+    }
+
+    /// How many times longer writing a block takes than reading it (R2W_FACTOR).
+    pub fn r2w_factor(&self) -> u32 {
+        1 << self.bits(28, 26)
+    }
+
+    /// The maximum write data block length in bytes (WRITE_BL_LEN).
+    pub fn write_block_len(&self) -> usize {
+        1 << self.bits(25, 22)
+    }
+
+    /// Partial blocks can be written (WRITE_BL_PARTIAL).
+    pub fn write_block_partial(&self) -> bool {
+        self.bit(21)
+    }
+
+    /// The contents of the card have been copied (COPY).
+    pub fn copy(&self) -> bool {
+        self.bit(14)
+    }
+
+    /// The entire card is permanently write protected (PERM_WRITE_PROTECT).
+    pub fn perm_write_protect(&self) -> bool {
+        self.bit(13)
+    }
+
+    /// The entire card is temporarily write protected (TMP_WRITE_PROTECT).
+    pub fn tmp_write_protect(&self) -> bool {
+        self.bit(12)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A 2 GB standard capacity card with 1024 byte blocks.
+    const CSD_V1: Csd = Csd([0x0026_0032, 0x5f5a_83ae, 0xfefb_cfff, 0x9280_40df]);
+    /// An 8 GB high capacity card.
+    const CSD_V2: Csd = Csd([0x400e_0032, 0x5b59_0000, 0x3b37_7f80, 0x0a40_40af]);
+
+    #[test]
+    fn version_1() {
+        let csd = CSD_V1;
+        assert_eq!(csd.version(), Ok(CsdVersion::V1));
+        assert_eq!(csd.taac(), Ok(1_500_000));
+        assert_eq!(csd.nsac(), 0);
+        assert_eq!(csd.tran_speed(), Ok(25_000_000));
+        assert_eq!(csd.ccc(), 0x5f5);
+        assert_eq!(csd.read_block_len(), 1024);
+        assert!(csd.read_block_partial());
+        assert!(!csd.write_block_misalign());
+        assert!(!csd.read_block_misalign());
+        assert!(!csd.dsr_implemented());
+        assert_eq!(csd.c_size(), Ok(0xebb));
+        assert_eq!(csd.c_size_mult(), Ok(7));
+        assert_eq!(csd.capacity(), Ok(3772 << 10));
+        assert!(csd.erase_block_enable());
+        assert_eq!(csd.sector_size(), 32);
+        assert_eq!(csd.wp_group_size(), 128);
+        assert!(csd.wp_group_enable());
+        assert_eq!(csd.r2w_factor(), 16);
+        assert_eq!(csd.write_block_len(), 1024);
+        assert!(!csd.write_block_partial());
+        assert!(csd.copy());
+        assert!(!csd.perm_write_protect());
+        assert!(!csd.tmp_write_protect());
+    }
+
+    #[test]
+    fn version_2() {
+        let csd = CSD_V2;
+        assert_eq!(csd.version(), Ok(CsdVersion::V2));
+        assert_eq!(csd.taac(), Ok(1_000_000));
+        assert_eq!(csd.nsac(), 0);
+        assert_eq!(csd.tran_speed(), Ok(25_000_000));
+        assert_eq!(csd.ccc(), 0x5b5);
+        assert_eq!(csd.read_block_len(), 512);
+        assert!(!csd.read_block_partial());
+        assert!(!csd.write_block_misalign());
+        assert!(!csd.read_block_misalign());
+        assert!(!csd.dsr_implemented());
+        assert_eq!(csd.c_size(), Ok(0x3b37));
+        assert_eq!(csd.c_size_mult(), Err(Error::InvalidValue));
+        assert_eq!(csd.capacity(), Ok(0x3b38 << 10));
+        assert!(csd.erase_block_enable());
+        assert_eq!(csd.sector_size(), 128);
+        assert_eq!(csd.wp_group_size(), 1);
+        assert!(!csd.wp_group_enable());
+        assert_eq!(csd.r2w_factor(), 4);
+        assert_eq!(csd.write_block_len(), 512);
+        assert!(!csd.write_block_partial());
+        assert!(csd.copy());
+        assert!(!csd.perm_write_protect());
+        assert!(!csd.tmp_write_protect());
+    }
+
+    #[test]
+    fn reserved_values() {
+        let mut csd = CSD_V2;
+        csd.0[0] |= 0x8000_0000;
+        assert_eq!(csd.version(), Err(Error::InvalidValue));
+        assert_eq!(csd.capacity(), Err(Error::InvalidValue));
+
+        // TRAN_SPEED units above 100 Mbit/s and a zero TAAC multiplier are reserved.
+        let csd = Csd([0x4006_0036, 0, 0, 0]);
+        assert_eq!(csd.taac(), Err(Error::InvalidValue));
+        assert_eq!(csd.tran_speed(), Err(Error::InvalidValue));
+    }
+}
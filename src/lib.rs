@@ -1,8 +1,81 @@
 #![no_std]
+#[cfg(any(
+    all(feature = "stm32h7", feature = "stm32l4plus"),
+    all(feature = "stm32h7", feature = "stm32l5"),
+    all(feature = "stm32h7", feature = "stm32u5"),
+    all(feature = "stm32l4plus", feature = "stm32l5"),
+    all(feature = "stm32l4plus", feature = "stm32u5"),
+    all(feature = "stm32l5", feature = "stm32u5"),
+))]
+compile_error!("Only one chip with an SDMMC v2 peripheral can be selected");
+#[cfg(feature = "alloc")]
+extern crate alloc;
+#[cfg(any(test, feature = "std"))]
+extern crate std;
+
 #[cfg(feature = "stm32l4x6")]
 mod stm32l4x6;
 #[cfg(feature = "stm32l4x6")]
-pub use stm32l4x6::{Config, Device, Pins};
+pub use stm32l4x6::{
+    Clock48, ClockPin, CommandPin, Data0Pin, Data1Pin, Data2Pin, Data3Pin, KernelClock, Pins,
+    Registers,
+};
+#[cfg(feature = "stm32f4")]
+mod stm32f4;
+#[cfg(feature = "stm32f4")]
+pub use stm32f4::{DmaStream, Sdio};
+mod cid;
+mod csd;
+mod protocol;
+#[cfg(any(
+    feature = "stm32f4",
+    feature = "stm32h7",
+    feature = "stm32l4plus",
+    feature = "stm32l5",
+    feature = "stm32u5"
+))]
+mod register;
+mod scr;
+#[cfg(any(
+    feature = "stm32h7",
+    feature = "stm32l4plus",
+    feature = "stm32l5",
+    feature = "stm32u5"
+))]
+mod sdmmc_v2;
+#[cfg(feature = "stm32h7")]
+mod stm32h7;
+#[cfg(feature = "stm32l4plus")]
+mod stm32l4plus;
+#[cfg(feature = "stm32l5")]
+mod stm32l5;
+#[cfg(feature = "stm32u5")]
+mod stm32u5;
+#[cfg(any(
+    feature = "stm32h7",
+    feature = "stm32l4plus",
+    feature = "stm32l5",
+    feature = "stm32u5"
+))]
+pub use sdmmc_v2::Sdmmc;
+#[cfg(feature = "std")]
+pub mod sim;
+mod slot;
+#[cfg(feature = "spi")]
+mod spi;
+mod switch;
+mod transfer;
+pub use cid::Cid;
+pub use csd::{Csd, CsdVersion};
+pub use protocol::{Config, Device, HostController, HostStatus};
+pub use scr::Scr;
+pub use slot::{CardDetect, CardEvent, CardPower, WriteProtect, WriteProtection};
+#[cfg(feature = "embedded-hal")]
+pub use slot::{PowerPin, SwitchPin};
+#[cfg(feature = "spi")]
+pub use spi::SpiDevice;
+pub use switch::{SwitchMode, SwitchStatus, ACCESS_MODE_GROUP, DEFAULT_SPEED, HIGH_SPEED};
+pub use transfer::{BlockBuffer, BlockBufferMut, Transfer};
 
 pub const BLOCK_SIZE: usize = 0x200;
 
@@ -10,7 +83,7 @@ pub type Block = [u8; BLOCK_SIZE];
 pub type BlockCount = u32;
 pub type BlockIndex = u32;
 
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub enum Error {
     /// Card does not respond at all, it is probably missing or unpowered.
     NoCard,
@@ -38,6 +111,49 @@ pub enum Error {
     NoOperation,
     /// The parsed value was not valid.
     InvalidValue,
+    /// The card does not support the requested function.
+    Unsupported,
+    /// The card reported a general or unknown error (ERROR).
+    CardError,
+    /// The command argument was out of range for this card (OUT_OF_RANGE).
+    OutOfRange,
+    /// The address does not match the block length (ADDRESS_ERROR).
+    AddressMisaligned,
+    /// The block length is not allowed for this card (BLOCK_LEN_ERROR).
+    InvalidBlockLength,
+    /// The erase commands were sent in the wrong order (ERASE_SEQ_ERROR).
+    EraseSequenceError,
+    /// An invalid selection of blocks was made for erasing (ERASE_PARAM).
+    InvalidEraseSelection,
+    /// A write protected block was written to (WP_VIOLATION).
+    WriteProtectViolation,
+    /// The card is locked by the host (CARD_IS_LOCKED).
+    CardLocked,
+    /// Locking or unlocking the card failed (LOCK_UNLOCK_FAILED).
+    LockUnlockFailed,
+    /// The CRC check of the previous command failed on the card (COM_CRC_ERROR).
+    CommandCRCFail,
+    /// The previous command was not legal in the state of the card (ILLEGAL_COMMAND).
+    IllegalCommand,
+    /// The card failed to correct the data using its internal ECC (CARD_ECC_FAILED).
+    ECCFailed,
+    /// The internal card controller reported an error (CC_ERROR).
+    CardControllerError,
+    /// The read only section of the CSD does not match the card, or the copy or permanent write
+    /// protect bits were being reverted (CSD_OVERWRITE).
+    CSDOverwrite,
+    /// Only part of the address space was erased because of write protected blocks
+    /// (WP_ERASE_SKIP).
+    WriteProtectEraseSkip,
+    /// The authentication sequence was wrong (AKE_SEQ_ERROR).
+    AuthenticationSequenceError,
+    /// The bus or a pin used to talk to the card reported an error.
+    BusError,
+    /// The card may not be written to, because of the write protect switch of its slot or the
+    /// write protect bits in its CSD.
+    WriteProtected,
+    /// The kernel clock of the card host is not running, or its frequency cannot be determined.
+    ClockConfiguration,
 }
 
 #[derive(Copy, Clone, Debug)]
@@ -47,17 +163,33 @@ pub enum CardVersion {
     V2HC,
 }
 
+impl CardVersion {
+    /// The address argument for `block`. High capacity cards are addressed in blocks, standard
+    /// capacity cards in bytes.
+    pub(crate) fn card_address(self, block: BlockIndex) -> Result<u32, Error> {
+        match self {
+            CardVersion::V2HC => Ok(block),
+            CardVersion::V1SC | CardVersion::V2SC => block
+                .checked_mul(BLOCK_SIZE as u32)
+                .ok_or(Error::InvalidValue),
+        }
+    }
+}
+
 #[derive(Copy, Clone, Debug)]
 #[allow(non_camel_case_types)]
 pub enum Command {
     GO_IDLE_STATE = 0,
     ALL_SEND_CID = 2,
     SEND_RELATIVE_ADDR = 3,
+    SWITCH_FUNC = 6,
     SELECT_CARD = 7,
     SEND_IF_COND = 8,
     SEND_CSD = 9,
     SEND_CID = 10,
+    STOP_TRANSMISSION = 12,
     SEND_STATUS = 13,
+    SET_BLOCKLEN = 16,
     READ_BLOCK = 17,
     READ_MULTIPLE_BLOCK = 18,
     SET_BLOCK_COUNT = 23,
@@ -67,6 +199,8 @@ pub enum Command {
     ERASE_WR_BLK_END = 33,
     ERASE = 38,
     APP_COMMAND = 55,
+    READ_OCR = 58,
+    CRC_ON_OFF = 59,
 }
 
 #[derive(Copy, Clone, Debug)]
@@ -76,18 +210,135 @@ pub enum AppCommand {
     SD_STATUS = 13,
     SET_WR_BLK_ERASE_COUNT = 23,
     SD_SEND_OP_COND = 41,
+    SEND_SCR = 51,
+}
+
+/// The format of the response a card sends to a command.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Response {
+    /// No response.
+    None,
+    /// Normal response containing the card status.
+    R1,
+    /// Normal response after which the card signals busy on the data line.
+    R1b,
+    /// Long response containing the CID or CSD register.
+    R2,
+    /// Operating conditions register, without CRC.
+    R3,
+    /// Published relative card address along with part of the card status.
+    R6,
+    /// Card interface condition.
+    R7,
+}
+
+/// The direction of the data transferred after a command, if any.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum DataDirection {
+    None,
+    /// Data is sent from the card to the host.
+    Read,
+    /// Data is sent from the host to the card.
+    Write,
+}
+
+impl Response {
+    /// The response is 136 bits long instead of 48.
+    pub fn is_long(self) -> bool {
+        self == Response::R2
+    }
+
+    /// The response contains the index of the command it responds to.
+    pub fn has_index(self) -> bool {
+        match self {
+            Response::None | Response::R2 | Response::R3 => false,
+            Response::R1 | Response::R1b | Response::R6 | Response::R7 => true,
+        }
+    }
+
+    /// The response is protected by a CRC that the host can check.
+    pub fn has_crc(self) -> bool {
+        match self {
+            Response::None | Response::R2 | Response::R3 => false,
+            Response::R1 | Response::R1b | Response::R6 | Response::R7 => true,
+        }
+    }
+
+    /// The card signals busy on the data line after the response until it is ready for the next
+    /// data transfer.
+    pub fn is_busy(self) -> bool {
+        self == Response::R1b
+    }
+
+    /// The card status contained in the first word of a response, if any. R6 responses only
+    /// contain some of the status bits, the others read as zero.
+    pub fn card_status(self, response: u32) -> Option<CardStatus> {
+        match self {
+            Response::R1 | Response::R1b => Some(CardStatus(response)),
+            Response::R6 => Some(CardStatus(
+                response & 0x1fff | (response & 0x2000) << 6 | (response & 0xc000) << 8,
+            )),
+            Response::None | Response::R2 | Response::R3 | Response::R7 => None,
+        }
+    }
+}
+
+impl Command {
+    /// The format of the response to this command.
+    pub fn response(self) -> Response {
+        use Command::*;
+        match self {
+            GO_IDLE_STATE => Response::None,
+            ALL_SEND_CID | SEND_CSD | SEND_CID => Response::R2,
+            SEND_RELATIVE_ADDR => Response::R6,
+            SEND_IF_COND => Response::R7,
+            SELECT_CARD | STOP_TRANSMISSION | ERASE => Response::R1b,
+            SWITCH_FUNC | SEND_STATUS | SET_BLOCKLEN | READ_BLOCK | READ_MULTIPLE_BLOCK
+            | SET_BLOCK_COUNT | WRITE_BLOCK | WRITE_MULTIPLE_BLOCK | ERASE_WR_BLK_START
+            | ERASE_WR_BLK_END | APP_COMMAND | CRC_ON_OFF => Response::R1,
+            // Only available in SPI mode.
+            READ_OCR => Response::R3,
+        }
+    }
+
+    /// The direction of the data transferred after this command.
+    pub fn data_direction(self) -> DataDirection {
+        use Command::*;
+        match self {
+            SWITCH_FUNC | READ_BLOCK | READ_MULTIPLE_BLOCK => DataDirection::Read,
+            WRITE_BLOCK | WRITE_MULTIPLE_BLOCK => DataDirection::Write,
+            _ => DataDirection::None,
+        }
+    }
+}
+
+impl AppCommand {
+    /// The format of the response to this command.
+    pub fn response(self) -> Response {
+        use AppCommand::*;
+        match self {
+            SD_SEND_OP_COND => Response::R3,
+            SET_BUS_WIDTH | SD_STATUS | SET_WR_BLK_ERASE_COUNT | SEND_SCR => Response::R1,
+        }
+    }
+
+    /// The direction of the data transferred after this command.
+    pub fn data_direction(self) -> DataDirection {
+        use AppCommand::*;
+        match self {
+            SD_STATUS | SEND_SCR => DataDirection::Read,
+            SET_BUS_WIDTH | SET_WR_BLK_ERASE_COUNT | SD_SEND_OP_COND => DataDirection::None,
+        }
+    }
 }
 
 #[derive(Copy, Clone, Debug)]
 pub enum BusWidth {
     Bits1,
     Bits4,
-}
-
-#[derive(Copy, Clone, Debug)]
-enum CSD {
-    V1([u32; 4]),
-    V2([u32; 4]),
+    /// Only available on hosts with eight data lines. SD cards do not support it, so a device
+    /// negotiating the bus width uses four lines instead.
+    Bits8,
 }
 
 #[derive(Copy, Clone, Debug)]
@@ -104,23 +355,190 @@ pub enum CardState {
     Reserved,
 }
 
-pub type CID = [u32; 4];
+#[derive(Copy, Clone)]
 pub struct SDStatus([u8; 64]);
+#[derive(Copy, Clone)]
 pub struct CardStatus(u32);
 
-const ERROR_MASK: u32 = 0xfff98004;
+const ERROR_MASK: u32 = 0xfdf98008;
+/// COM_CRC_ERROR and ILLEGAL_COMMAND. These refer to an earlier command, which received no
+/// response.
+const PREVIOUS_COMMAND_ERROR_MASK: u32 = 0x00c00000;
+
+/// The card status bits and the errors they map to, in order of precedence.
+const STATUS_ERRORS: [(u32, Error); 16] = [
+    (31, Error::OutOfRange),
+    (30, Error::AddressMisaligned),
+    (29, Error::InvalidBlockLength),
+    (28, Error::EraseSequenceError),
+    (27, Error::InvalidEraseSelection),
+    (26, Error::WriteProtectViolation),
+    (25, Error::CardLocked),
+    (24, Error::LockUnlockFailed),
+    (23, Error::CommandCRCFail),
+    (22, Error::IllegalCommand),
+    (21, Error::ECCFailed),
+    (20, Error::CardControllerError),
+    (19, Error::CardError),
+    (16, Error::CSDOverwrite),
+    (15, Error::WriteProtectEraseSkip),
+    (3, Error::AuthenticationSequenceError),
+];
+
+/// Extract the bits `high..=low` of a register that was received most significant word first,
+/// with the bits numbered as in the specification.
+fn register_bits(words: &[u32], high: usize, low: usize) -> u32 {
+    let mut value = 0;
+    for bit in (low..=high).rev() {
+        let word = words[words.len() - 1 - bit / 32];
+        value = value << 1 | (word >> (bit % 32)) & 1;
+    }
+
+    value
+}
+
+impl core::fmt::Debug for CardStatus {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "CardStatus(")?;
+        write!(f, "state={:?}, ", self.state())?;
+        write!(f, "ready_for_data={:?}, ", self.ready_for_data())?;
+        write!(f, "app_cmd={:?}, ", self.app_cmd())?;
+        write!(f, "card_is_locked={:?}, ", self.card_is_locked())?;
+        write!(f, "card_ecc_disabled={:?}, ", self.card_ecc_disabled())?;
+        write!(f, "erase_reset={:?}, ", self.erase_reset())?;
+        write!(f, "error={:?})", self.error())?;
+        Ok(())
+    }
+}
 
 impl CardStatus {
+    fn bit(&self, bit: u32) -> bool {
+        (self.0 >> bit) & 1 != 0
+    }
+
+    fn masked_error(&self, mask: u32) -> Option<Error> {
+        if self.0 & mask == 0 {
+            return None;
+        }
+
+        // Being locked is not an error in itself, but it is the likely cause of any other error.
+        let bits = self.0 & (mask | 1 << 25);
+        STATUS_ERRORS
+            .iter()
+            .find(|(bit, _)| (bits >> bit) & 1 != 0)
+            .map(|(_, error)| *error)
+    }
+
     pub fn any_error(&self) -> bool {
         self.0 & ERROR_MASK != 0
     }
 
+    /// The most important error reported in this status, if any.
+    pub fn error(&self) -> Option<Error> {
+        self.masked_error(ERROR_MASK)
+    }
+
+    /// The most important error caused by the command this status was a response to, if any.
+    pub fn command_error(&self) -> Option<Error> {
+        self.masked_error(ERROR_MASK & !PREVIOUS_COMMAND_ERROR_MASK)
+    }
+
+    /// The command argument was out of range for this card (OUT_OF_RANGE).
+    pub fn out_of_range(&self) -> bool {
+        self.bit(31)
+    }
+
+    /// The address does not match the block length (ADDRESS_ERROR).
+    pub fn address_error(&self) -> bool {
+        self.bit(30)
+    }
+
+    /// The block length is not allowed for this card (BLOCK_LEN_ERROR).
+    pub fn block_len_error(&self) -> bool {
+        self.bit(29)
+    }
+
+    /// The erase commands were sent in the wrong order (ERASE_SEQ_ERROR).
+    pub fn erase_seq_error(&self) -> bool {
+        self.bit(28)
+    }
+
+    /// An invalid selection of blocks was made for erasing (ERASE_PARAM).
+    pub fn erase_param(&self) -> bool {
+        self.bit(27)
+    }
+
+    /// A write protected block was written to (WP_VIOLATION).
+    pub fn wp_violation(&self) -> bool {
+        self.bit(26)
+    }
+
+    /// The card is locked by the host (CARD_IS_LOCKED).
+    pub fn card_is_locked(&self) -> bool {
+        self.bit(25)
+    }
+
+    /// Locking or unlocking the card failed (LOCK_UNLOCK_FAILED).
+    pub fn lock_unlock_failed(&self) -> bool {
+        self.bit(24)
+    }
+
+    /// The CRC check of the previous command failed (COM_CRC_ERROR).
+    pub fn com_crc_error(&self) -> bool {
+        self.bit(23)
+    }
+
+    /// The previous command was not legal in the state of the card (ILLEGAL_COMMAND).
+    pub fn illegal_command(&self) -> bool {
+        self.bit(22)
+    }
+
+    /// The card failed to correct the data using its internal ECC (CARD_ECC_FAILED).
+    pub fn card_ecc_failed(&self) -> bool {
+        self.bit(21)
+    }
+
+    /// The internal card controller reported an error (CC_ERROR).
+    pub fn cc_error(&self) -> bool {
+        self.bit(20)
+    }
+
+    /// A general or unknown error occurred (ERROR).
+    pub fn error_bit(&self) -> bool {
+        self.bit(19)
+    }
+
+    /// The CSD could not be overwritten (CSD_OVERWRITE).
+    pub fn csd_overwrite(&self) -> bool {
+        self.bit(16)
+    }
+
+    /// Write protected blocks were skipped while erasing (WP_ERASE_SKIP).
+    pub fn wp_erase_skip(&self) -> bool {
+        self.bit(15)
+    }
+
+    /// The command was executed without using the internal ECC (CARD_ECC_DISABLED).
+    pub fn card_ecc_disabled(&self) -> bool {
+        self.bit(14)
+    }
+
+    /// An erase sequence was cleared by a command outside of it (ERASE_RESET).
+    pub fn erase_reset(&self) -> bool {
+        self.bit(13)
+    }
+
+    /// The authentication sequence was wrong (AKE_SEQ_ERROR).
+    pub fn ake_seq_error(&self) -> bool {
+        self.bit(3)
+    }
+
     pub fn ready_for_data(&self) -> bool {
-        (self.0 >> 8) & 1 != 0
+        self.bit(8)
     }
 
     pub fn app_cmd(&self) -> bool {
-        (self.0 >> 5) & 1 != 0
+        self.bit(5)
     }
 
     pub fn state(&self) -> CardState {
@@ -199,6 +617,11 @@ impl SDStatus {
         (self.0[0x0d] as usize) >> 2
     }
 
+    /// The number of seconds added to every erase, independent of its size.
+    pub fn erase_offset(&self) -> usize {
+        (self.0[0x0d] & 3) as usize
+    }
+
     /// SD card supports discard.
     pub fn discard_support(&self) -> bool {
         (self.0[0x18] >> 1) & 1 != 0
@@ -210,28 +633,15 @@ impl SDStatus {
     }
 }
 
-impl CSD {
-    fn capacity(&self) -> BlockCount {
-        match self {
-            CSD::V1(words) => {
-                let c_size = (words[2] >> 30 | (words[1] & 0x3ff) << 2) + 1;
-                let c_size_mult = ((words[2] & 0x0003_8000) >> 15) + 2;
-                c_size << c_size_mult
-            }
-            CSD::V2(words) => {
-                let c_size = (words[2] >> 16) + 1;
-                c_size << 10
-            }
-        }
-    }
-}
-
 pub trait CardHost {
     /// Initialize the SD card.
     fn init_card(&mut self) -> nb::Result<(), Error>;
 
     /// Return the card identification number.
-    fn card_id(&mut self) -> Result<CID, Error>;
+    fn card_id(&mut self) -> Result<Cid, Error>;
+
+    /// Return the card specific data.
+    fn card_specific_data(&mut self) -> Result<Csd, Error>;
 
     /// Return the card size in blocks.
     fn card_size(&mut self) -> Result<BlockCount, Error>;
@@ -242,26 +652,187 @@ pub trait CardHost {
     /// Read the SD Status register.
     fn read_sd_status(&mut self) -> Result<SDStatus, Error>;
 
+    /// Read the SD Configuration Register.
+    fn read_scr(&mut self) -> Result<Scr, Error>;
+
+    /// Check or switch the function of a function group with CMD6. Functions in all other groups
+    /// are left unchanged. Switching the access mode group to high speed also raises the clock of
+    /// the card host.
+    fn switch_function(
+        &mut self,
+        mode: SwitchMode,
+        group: u8,
+        function: u8,
+    ) -> Result<SwitchStatus, Error>;
+
+    /// Switch the card to high speed mode if it supports it.
+    fn enable_high_speed(&mut self) -> Result<(), Error> {
+        // Switch function is part of command class 10.
+        if (self.card_specific_data()?.ccc() >> 10) & 1 == 0 {
+            return Err(Error::Unsupported);
+        }
+
+        let status = self.switch_function(SwitchMode::Check, ACCESS_MODE_GROUP, HIGH_SPEED)?;
+        if !status.is_supported(ACCESS_MODE_GROUP, HIGH_SPEED) {
+            return Err(Error::Unsupported);
+        }
+
+        let status = self.switch_function(SwitchMode::Switch, ACCESS_MODE_GROUP, HIGH_SPEED)?;
+        match status.selected(ACCESS_MODE_GROUP) {
+            Ok(HIGH_SPEED) => Ok(()),
+            Ok(_) => Err(Error::Unsupported),
+            Err(e) => Err(e),
+        }
+    }
+
     /// Erase blocks on the SD card.
     fn erase(&mut self, start: BlockIndex, end: BlockIndex) -> Result<(), Error>;
 
-    /// Read a block from the SD card into memory. This function is unsafe because it writes to the
-    /// passed memory block after the end of its lifetime. Make sure to keep it around and avoid
-    /// reading or writing to it until the operation is finished.
+    /// Read a block from the SD card into memory. See `start_read` for a safe alternative.
+    ///
+    /// # Safety
+    ///
+    /// The DMA writes to the passed memory block after the end of its lifetime. Make sure to keep
+    /// it around and avoid reading or writing to it until the operation is finished.
     unsafe fn read_block(&mut self, block: &mut Block, address: BlockIndex) -> Result<(), Error>;
 
-    /// Write multiple blocks from the SD card into memory. This function is unsafe because it
-    /// reads from the passed memory blocks after the end of their lifetime. Make sure to keep them
-    /// around and avoid writing to them until the operation is finished.
+    /// Read multiple consecutive blocks from the SD card into memory in a single transfer. See
+    /// `start_read` for a safe alternative.
+    ///
+    /// # Safety
+    ///
+    /// The DMA writes to the passed memory blocks after the end of their lifetime. Make sure to
+    /// keep them around and avoid reading or writing to them until the operation is finished.
+    unsafe fn read_blocks(
+        &mut self,
+        blocks: &mut [Block],
+        address: BlockIndex,
+    ) -> Result<(), Error>;
+
+    /// Write multiple blocks from memory to the SD card. See `start_write` for a safe alternative.
+    ///
+    /// # Safety
+    ///
+    /// The DMA reads from the passed memory blocks after the end of their lifetime. Make sure to
+    /// keep them around and avoid writing to them until the operation is finished.
     unsafe fn write_blocks(&mut self, blocks: &[Block], address: BlockIndex) -> Result<(), Error>;
 
-    /// Write a block from the SD card into memory. This function is unsafe because it reads from the
-    /// passed memory block after the end of its lifetime. Make sure to keep it around and avoid
-    /// writing to it until the operation is finished.
+    /// Write a block from memory to the SD card. See `start_write` for a safe alternative.
+    ///
+    /// # Safety
+    ///
+    /// The DMA reads from the passed memory block after the end of its lifetime. Make sure to keep
+    /// it around and avoid writing to it until the operation is finished.
     unsafe fn write_block(&mut self, block: &Block, address: BlockIndex) -> Result<(), Error> {
         self.write_blocks(core::slice::from_ref(block), address)
     }
 
+    /// Start reading blocks from the SD card into an owned buffer. The buffer is returned by the
+    /// transfer once the read has finished, or immediately along with the error if the read could
+    /// not be started.
+    fn start_read<B: BlockBufferMut>(
+        &mut self,
+        buffer: B,
+        address: BlockIndex,
+    ) -> Result<Transfer<'_, Self, B>, (Error, B)>
+    where
+        Self: Sized,
+    {
+        Transfer::read(self, buffer, address)
+    }
+
+    /// Start writing the blocks in an owned buffer to the SD card. The buffer is returned by the
+    /// transfer once the write has finished, or immediately along with the error if the write
+    /// could not be started.
+    fn start_write<B: BlockBuffer>(
+        &mut self,
+        buffer: B,
+        address: BlockIndex,
+    ) -> Result<Transfer<'_, Self, B>, (Error, B)>
+    where
+        Self: Sized,
+    {
+        Transfer::write(self, buffer, address)
+    }
+
     /// Check the result of a read or write operation.
     fn result(&mut self) -> nb::Result<(), Error>;
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn card_status_fields() {
+        type Field = fn(&CardStatus) -> bool;
+        let fields: [(u32, Field); 20] = [
+            (31, CardStatus::out_of_range),
+            (30, CardStatus::address_error),
+            (29, CardStatus::block_len_error),
+            (28, CardStatus::erase_seq_error),
+            (27, CardStatus::erase_param),
+            (26, CardStatus::wp_violation),
+            (25, CardStatus::card_is_locked),
+            (24, CardStatus::lock_unlock_failed),
+            (23, CardStatus::com_crc_error),
+            (22, CardStatus::illegal_command),
+            (21, CardStatus::card_ecc_failed),
+            (20, CardStatus::cc_error),
+            (19, CardStatus::error_bit),
+            (16, CardStatus::csd_overwrite),
+            (15, CardStatus::wp_erase_skip),
+            (14, CardStatus::card_ecc_disabled),
+            (13, CardStatus::erase_reset),
+            (8, CardStatus::ready_for_data),
+            (5, CardStatus::app_cmd),
+            (3, CardStatus::ake_seq_error),
+        ];
+        for &(bit, _) in &fields {
+            let status = CardStatus(1 << bit);
+            for &(other, field) in &fields {
+                assert_eq!(field(&status), other == bit, "bit {}", other);
+            }
+            assert_eq!(status.any_error(), ERROR_MASK >> bit & 1 != 0);
+        }
+    }
+
+    #[test]
+    fn card_status_state() {
+        // The response to SEND_STATUS after a finished write.
+        let status = CardStatus(0x0000_0900);
+        assert!(matches!(status.state(), CardState::Transmit));
+        assert!(status.ready_for_data());
+        assert!(!status.app_cmd());
+        assert!(!status.any_error());
+        assert_eq!(status.error(), None);
+
+        // The response to APP_CMD in the standby state.
+        let status = CardStatus(0x0000_0720);
+        assert!(matches!(status.state(), CardState::Standby));
+        assert!(status.app_cmd());
+        assert!(status.ready_for_data());
+        assert!(matches!(CardStatus(0xe00).state(), CardState::Program));
+        assert!(matches!(CardStatus(0x1e00).state(), CardState::Reserved));
+    }
+
+    #[test]
+    fn card_status_errors() {
+        let status = CardStatus(0x8040_0900);
+        assert_eq!(status.error(), Some(Error::OutOfRange));
+        assert_eq!(status.command_error(), Some(Error::OutOfRange));
+
+        // An illegal command bit refers to the command before.
+        let status = CardStatus(0x0040_0900);
+        assert_eq!(status.error(), Some(Error::IllegalCommand));
+        assert_eq!(status.command_error(), None);
+
+        // Being locked only shows up as the cause of another error.
+        assert_eq!(CardStatus(0x0200_0900).error(), None);
+        assert_eq!(CardStatus(0x0210_0900).error(), Some(Error::CardLocked));
+        assert_eq!(
+            CardStatus(0x0000_0008).error(),
+            Some(Error::AuthenticationSequenceError)
+        );
+    }
+}
@@ -0,0 +1,1342 @@
+//! The chip independent part of the SD protocol, which drives a card through a host controller.
+
+use crate::slot::Debounce;
+use crate::Error::*;
+use crate::{
+    AppCommand, Block, BlockCount, BlockIndex, BusWidth, CardDetect, CardEvent, CardHost,
+    CardPower, CardState, CardStatus, CardVersion, Cid, Command, Csd, DataDirection, Error,
+    Response, SDStatus, Scr, SwitchMode, SwitchStatus, WriteProtect, WriteProtection,
+    ACCESS_MODE_GROUP, BLOCK_SIZE, DEFAULT_SPEED,
+};
+use nb::block;
+use nb::Error::{Other, WouldBlock};
+
+pub(crate) const SEND_IF_COND_PATTERN: u32 = 0x0000_01aa;
+/// The highest card clock frequency allowed during identification.
+const IDENTIFICATION_CLOCK: u32 = 400_000;
+/// The clock divider used for identification when the kernel clock frequency is not known.
+const IDENTIFICATION_CLOCK_DIVIDER: u16 = 0x80;
+/// The highest card clock frequency in default speed mode, assumed when the CSD has no valid
+/// TRAN_SPEED.
+const DEFAULT_SPEED_CLOCK: u32 = 25_000_000;
+/// The highest card clock frequency in high speed mode.
+const HIGH_SPEED_CLOCK: u32 = 50_000_000;
+/// The longest a card may take to start sending read data.
+const READ_TIMEOUT_MS: u64 = 100;
+/// The longest a card may stay busy programming written data.
+const WRITE_TIMEOUT_MS: u64 = 250;
+/// The same for SDXC cards.
+const SDXC_WRITE_TIMEOUT_MS: u64 = 500;
+/// Cards with more blocks than this, 32 GiB, are SDXC cards.
+const SDXC_BLOCKS: BlockCount = 64 * 1024 * 1024;
+/// The fewest card clock cycles a SEND_STATUS command and its response take. Without a timer,
+/// this is counted for every poll of a busy card, which undercounts the time that passed when the
+/// polls are further apart. So the timeout may fire late but never early.
+const SEND_STATUS_CYCLES: u64 = 96;
+/// How long the card is kept off during a power cycle, so its supply can drop below 0.5 V.
+const POWER_OFF_MS: u32 = 10;
+/// How long the supply may take to ramp up to its operating voltage, plus the 1 ms the card
+/// needs after that.
+const POWER_RAMP_MS: u32 = 36;
+
+/// The longest a card may stay busy programming a written block, according to its capacity.
+pub(crate) fn max_write_ms(csd: &Csd) -> u64 {
+    match csd.capacity() {
+        Ok(blocks) if blocks > SDXC_BLOCKS => SDXC_WRITE_TIMEOUT_MS,
+        _ => WRITE_TIMEOUT_MS,
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+enum State {
+    Uninitialized,
+    Init1(bool),
+    Ready,
+    Reading,
+    Writing,
+    /// Waiting for the card to finish programming before the next chunk of a write is sent.
+    Programming,
+    Erasing,
+}
+
+/// How long the card may stay busy programming or erasing before the operation times out.
+#[derive(Copy, Clone, Debug)]
+enum BusyLimit {
+    /// The value of the millisecond timer at which the operation times out.
+    Deadline(u32),
+    /// The card clock cycles left, counted down by `SEND_STATUS_CYCLES` for every poll.
+    Cycles(u64),
+}
+
+/// The part of a read or write that has not been handed to the DMA yet.
+#[derive(Copy, Clone, Debug)]
+struct Chunks {
+    blocks: *mut Block,
+    count: usize,
+    address: BlockIndex,
+}
+
+/// The status flags of a host controller, laid out as in the STA register of the STM32 SDMMC and
+/// SDIO peripherals. Controllers with a different layout translate their flags to this one.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct HostStatus(pub u32);
+
+impl HostStatus {
+    pub const CCRCFAIL: u32 = 1 << 0;
+    pub const DCRCFAIL: u32 = 1 << 1;
+    pub const CTIMEOUT: u32 = 1 << 2;
+    pub const DTIMEOUT: u32 = 1 << 3;
+    pub const TXUNDERR: u32 = 1 << 4;
+    pub const RXOVERR: u32 = 1 << 5;
+    pub const CMDREND: u32 = 1 << 6;
+    pub const CMDSENT: u32 = 1 << 7;
+    pub const DATAEND: u32 = 1 << 8;
+    pub const DBCKEND: u32 = 1 << 10;
+    pub const CMDACT: u32 = 1 << 11;
+    pub const TXACT: u32 = 1 << 12;
+    pub const RXACT: u32 = 1 << 13;
+
+    fn flag(&self, flag: u32) -> bool {
+        self.0 & flag != 0
+    }
+
+    /// A response was received but its CRC check failed.
+    pub fn ccrcfail(&self) -> bool {
+        self.flag(Self::CCRCFAIL)
+    }
+
+    /// A data block was received but its CRC check failed.
+    pub fn dcrcfail(&self) -> bool {
+        self.flag(Self::DCRCFAIL)
+    }
+
+    /// No response was received in time.
+    pub fn ctimeout(&self) -> bool {
+        self.flag(Self::CTIMEOUT)
+    }
+
+    /// The data transfer did not finish in time.
+    pub fn dtimeout(&self) -> bool {
+        self.flag(Self::DTIMEOUT)
+    }
+
+    /// The transmit FIFO ran empty during a write.
+    pub fn txunderr(&self) -> bool {
+        self.flag(Self::TXUNDERR)
+    }
+
+    /// The receive FIFO overflowed during a read.
+    pub fn rxoverr(&self) -> bool {
+        self.flag(Self::RXOVERR)
+    }
+
+    /// A response was received and its CRC check passed.
+    pub fn cmdrend(&self) -> bool {
+        self.flag(Self::CMDREND)
+    }
+
+    /// A command that expects no response was sent.
+    pub fn cmdsent(&self) -> bool {
+        self.flag(Self::CMDSENT)
+    }
+
+    /// All data was transferred.
+    pub fn dataend(&self) -> bool {
+        self.flag(Self::DATAEND)
+    }
+
+    /// The last data block was transferred and its CRC check passed.
+    pub fn dbckend(&self) -> bool {
+        self.flag(Self::DBCKEND)
+    }
+
+    /// A command is being sent.
+    pub fn cmdact(&self) -> bool {
+        self.flag(Self::CMDACT)
+    }
+
+    /// Data is being sent.
+    pub fn txact(&self) -> bool {
+        self.flag(Self::TXACT)
+    }
+
+    /// Data is being received.
+    pub fn rxact(&self) -> bool {
+        self.flag(Self::RXACT)
+    }
+}
+
+/// The operations on an SD host controller and its DMA that the protocol needs. Each supported chip
+/// implements this for its peripherals, and a mock can implement it to run the protocol on a host.
+pub trait HostController {
+    /// The largest number of blocks the data path and DMA can transfer at once. Longer transfers
+    /// are split into chunks of at most this many blocks.
+    const MAX_BLOCKS: usize = 0x1ff_ffff / BLOCK_SIZE;
+
+    /// The largest clock divider the host supports.
+    const MAX_CLOCK_DIVIDER: u16 = 257;
+
+    /// The card clock frequency the host makes from a kernel clock frequency with a clock
+    /// divider. Dividers below two bypass the divider.
+    fn card_clock(kernel_clock: u32, clock_divider: u16) -> u32 {
+        match clock_divider {
+            0 | 1 => kernel_clock,
+            divider => kernel_clock / divider as u32,
+        }
+    }
+
+    /// Power on the card and configure the clock, the width of the data bus and the number of
+    /// card clock cycles to wait for data.
+    fn configure(&mut self, clock_divider: u16, bus_width: BusWidth, data_timeout: u32);
+
+    /// Start sending a command that expects a response of the given format. `data` is the
+    /// direction of the data transfer the command starts, if any.
+    fn send_command(&mut self, index: u8, arg: u32, response: Response, data: DataDirection);
+
+    /// The command index of the last response.
+    fn response_index(&self) -> u8;
+
+    /// The last response. Short responses are in the first word.
+    fn response(&self) -> [u32; 4];
+
+    /// The current status flags.
+    fn status(&self) -> HostStatus;
+
+    /// Clear the status flags of the last command or data transfer.
+    fn clear_status(&mut self);
+
+    /// Program the data path to transfer `len` bytes in blocks of `block_size` bytes and enable
+    /// it.
+    fn start_data(&mut self, len: usize, block_size: usize, direction: DataDirection);
+
+    /// Program the DMA to transfer `len` bytes between `buffer` and the data FIFO and enable it.
+    ///
+    /// # Safety
+    ///
+    /// The buffer must remain valid until the DMA is stopped or the transfer has finished.
+    unsafe fn start_dma(&mut self, buffer: *mut u8, len: usize, direction: DataDirection);
+
+    /// Disable the DMA.
+    fn stop_dma(&mut self);
+
+    /// Reset the peripherals to their power on state.
+    fn reset(&mut self);
+}
+
+/// An SD card behind a host controller `H`, in a slot with an optional card detect switch `D`,
+/// write protect switch `W` and power switch `P`.
+pub struct Device<H, D = (), W = (), P = ()> {
+    pub(crate) host: H,
+    detect: D,
+    debounce: Debounce,
+    protect: W,
+    power: P,
+    /// The number of times in a row that initializing the card failed.
+    init_failures: u8,
+    /// The number of times SD_SEND_OP_COND found the card busy during this initialization.
+    op_cond_polls: u32,
+    config: Config,
+    state: State,
+    rca: u32,
+    /// Card Specific Data
+    csd: Csd,
+    /// SD Configuration Register, which tells which optional commands the card supports.
+    scr: Scr,
+    /// SD Status read during initialization, which tells how long erasing may take.
+    sd_status: SDStatus,
+    cid: Cid,
+    card_version: CardVersion,
+    bus_width: BusWidth,
+    /// The frequency of the clock the host divides to clock the card, if it is known.
+    kernel_clock: Option<u32>,
+    clock_divider: u16,
+    /// A free-running millisecond timer, to time how long the card stays busy.
+    timer: Option<fn() -> u32>,
+    /// How long the card may stay busy with the ongoing write or erase.
+    busy_limit: Option<BusyLimit>,
+    /// The ongoing transfer was started without SET_BLOCK_COUNT and ends with
+    /// STOP_TRANSMISSION.
+    stop_transmission: bool,
+    remaining: Chunks,
+}
+
+// The only raw pointer in the device refers to the buffer of the ongoing transfer, which the caller
+// of `read_blocks` or `write_blocks` has promised to keep around until it is finished.
+unsafe impl<H: Send, D: Send, W: Send, P: Send> Send for Device<H, D, W, P> {}
+
+pub struct Config {
+    /// The width of the data bus in bits, either one or four. When negotiating, this is the
+    /// widest bus that will be used.
+    pub bus_width: BusWidth,
+    /// Read the bus widths the card supports from its SCR during initialization and use the
+    /// widest one allowed by `bus_width`.
+    pub negotiate_bus_width: bool,
+    /// Value to divide the clock speed by when the kernel clock frequency is not known. Zero or
+    /// one means bypass clock divider, and dividers above `HostController::MAX_CLOCK_DIVIDER`
+    /// are limited to it.
+    pub clock_divider: u16,
+    /// Value to divide the clock speed by once the card has been switched to high speed mode,
+    /// when the kernel clock frequency is not known.
+    pub high_speed_clock_divider: u16,
+    /// The highest card clock frequency to use for data transfers when the kernel clock
+    /// frequency is known. The card may limit it further with the TRAN_SPEED in its CSD.
+    pub data_clock: u32,
+    /// The highest card clock frequency to use once the card has been switched to high speed
+    /// mode, when the kernel clock frequency is known.
+    pub high_speed_clock: u32,
+    /// The number of clock cycles to wait for data transfer to complete when the kernel clock
+    /// frequency is not known. Otherwise the timeout of each transfer is derived from the CSD.
+    pub data_timeout: u32,
+    /// The number of consecutive samples of the card detect switch that have to agree before an
+    /// insertion or removal is reported.
+    pub card_detect_debounce: u8,
+    /// The number of times SD_SEND_OP_COND may find the card busy before initialization fails
+    /// with a timeout. The spec allows a card one second, which is about 2000 polls at 400 kHz.
+    pub op_cond_polls: u32,
+    /// The number of initializations in a row that may fail before `init_card` power cycles the
+    /// card and tries once more. Zero disables this.
+    pub power_cycle_after: u8,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            bus_width: BusWidth::Bits1,
+            negotiate_bus_width: false,
+            clock_divider: 4,
+            high_speed_clock_divider: 0,
+            data_clock: DEFAULT_SPEED_CLOCK,
+            high_speed_clock: HIGH_SPEED_CLOCK,
+            data_timeout: 0x1000000,
+            card_detect_debounce: 3,
+            op_cond_polls: 2000,
+            power_cycle_after: 3,
+        }
+    }
+}
+
+impl<H: HostController> Device<H> {
+    /// Create a device that accesses the card through `host`.
+    pub fn with_host(host: H, config: Config) -> Self {
+        let clock_divider = config.clock_divider;
+        Device {
+            host,
+            detect: (),
+            debounce: Debounce::new(true),
+            protect: (),
+            power: (),
+            init_failures: 0,
+            op_cond_polls: 0,
+            config,
+            state: State::Uninitialized,
+            rca: 0,
+            csd: Csd([0; 4]),
+            scr: Scr([0; 8]),
+            sd_status: SDStatus([0; 64]),
+            cid: Cid([0; 4]),
+            card_version: CardVersion::V1SC,
+            bus_width: BusWidth::Bits1,
+            kernel_clock: None,
+            clock_divider,
+            timer: None,
+            busy_limit: None,
+            stop_transmission: false,
+            remaining: Chunks {
+                blocks: core::ptr::null_mut(),
+                count: 0,
+                address: 0,
+            },
+        }
+    }
+}
+
+impl<H: HostController, W: WriteProtect, P: CardPower> Device<H, (), W, P> {
+    /// Watch a card detect switch. The switch is sampled once to find out whether a card is
+    /// present.
+    pub fn with_card_detect<D: CardDetect>(self, mut detect: D) -> Device<H, D, W, P> {
+        let debounce = Debounce::new(detect.card_present());
+        let (mut device, ()) = self.map_switches(|(), protect, power| (detect, protect, power, ()));
+        device.debounce = debounce;
+        device
+    }
+}
+
+impl<H: HostController, D: CardDetect, P: CardPower> Device<H, D, (), P> {
+    /// Refuse writes while the write protect switch is set.
+    pub fn with_write_protect<W: WriteProtect>(self, protect: W) -> Device<H, D, W, P> {
+        self.map_switches(|detect, (), power| (detect, protect, power, ()))
+            .0
+    }
+}
+
+impl<H: HostController, D: CardDetect, W: WriteProtect> Device<H, D, W, ()> {
+    /// Control the power of the card with a switch, so it can be power cycled.
+    pub fn with_power<P: CardPower>(self, power: P) -> Device<H, D, W, P> {
+        self.map_switches(|detect, protect, ()| (detect, protect, power, ()))
+            .0
+    }
+}
+
+impl<H: HostController, D: CardDetect, W: WriteProtect, P: CardPower> Device<H, D, W, P> {
+    /// Stop watching the card detect switch and get it back.
+    pub fn without_card_detect(self) -> (Device<H, (), W, P>, D) {
+        let (mut device, detect) =
+            self.map_switches(|detect, protect, power| ((), protect, power, detect));
+        device.debounce = Debounce::new(true);
+        (device, detect)
+    }
+
+    /// Stop watching the write protect switch and get it back.
+    pub fn without_write_protect(self) -> (Device<H, D, (), P>, W) {
+        self.map_switches(|detect, protect, power| (detect, (), power, protect))
+    }
+
+    /// Give up control of the power of the card and get the power switch back.
+    pub fn without_power(self) -> (Device<H, D, W>, P) {
+        self.map_switches(|detect, protect, power| (detect, protect, (), power))
+    }
+
+    /// Move the device over to other switches, made from the current ones by `f`.
+    fn map_switches<D2, W2, P2, R>(
+        self,
+        f: impl FnOnce(D, W, P) -> (D2, W2, P2, R),
+    ) -> (Device<H, D2, W2, P2>, R) {
+        let (detect, protect, power, rest) = f(self.detect, self.protect, self.power);
+        let device = Device {
+            host: self.host,
+            detect,
+            debounce: self.debounce,
+            protect,
+            power,
+            init_failures: self.init_failures,
+            op_cond_polls: self.op_cond_polls,
+            config: self.config,
+            state: self.state,
+            rca: self.rca,
+            csd: self.csd,
+            scr: self.scr,
+            sd_status: self.sd_status,
+            cid: self.cid,
+            card_version: self.card_version,
+            bus_width: self.bus_width,
+            kernel_clock: self.kernel_clock,
+            clock_divider: self.clock_divider,
+            timer: self.timer,
+            busy_limit: self.busy_limit,
+            stop_transmission: self.stop_transmission,
+            remaining: self.remaining,
+        };
+        (device, rest)
+    }
+
+    /// The host controller the device accesses the card through.
+    pub fn host(&self) -> &H {
+        &self.host
+    }
+
+    pub fn host_mut(&mut self) -> &mut H {
+        &mut self.host
+    }
+
+    pub(crate) fn reset(&mut self) {
+        self.state = State::Uninitialized;
+        self.host.reset();
+    }
+
+    /// Whether a card is in the slot according to the debounced card detect switch. Without a
+    /// switch, a card is always assumed to be present.
+    pub fn card_present(&self) -> bool {
+        self.debounce.present()
+    }
+
+    /// The write protection of the card, from the write protect switch and the CSD read during
+    /// initialization.
+    pub fn write_protection(&mut self) -> WriteProtection {
+        WriteProtection {
+            switch: self.protect.write_protected(),
+            temporary: self.csd.tmp_write_protect(),
+            permanent: self.csd.perm_write_protect(),
+        }
+    }
+
+    /// Refuse to change the contents of a write protected card.
+    fn check_writable(&mut self) -> Result<(), Error> {
+        match self.write_protection().is_protected() {
+            true => Err(WriteProtected),
+            false => Ok(()),
+        }
+    }
+
+    /// Turn the card off and on again, which resets it even when it no longer responds to
+    /// commands. Any ongoing operation is aborted, and the card has to be initialized again.
+    /// Without a power switch, only the host controller is reset.
+    pub fn power_cycle(&mut self) -> Result<(), Error> {
+        self.abort();
+        // Stop driving the clock and command lines, so they do not power the card.
+        self.host.reset();
+        self.power.set_power(false)?;
+        self.power.delay_ms(POWER_OFF_MS);
+        self.power.set_power(true)?;
+        self.power.delay_ms(POWER_RAMP_MS);
+
+        // The card needs 74 clock cycles after the supply is stable before it accepts commands.
+        // A command without a response takes 48 cycles, so two are enough. The card may ignore
+        // them.
+        self.clock_divider = self.identification_divider();
+        self.init_peri(self.clock_divider);
+        let cmd = Command::GO_IDLE_STATE;
+        for _ in 0..2 {
+            self.send(cmd as u8, cmd.response(), cmd.data_direction(), 0)
+                .ok();
+        }
+        Ok(())
+    }
+
+    /// Stop the ongoing operation and forget the card.
+    fn abort(&mut self) {
+        self.host.stop_dma();
+        self.host.clear_status();
+        self.remaining.count = 0;
+        self.stop_transmission = false;
+        self.state = State::Uninitialized;
+    }
+
+    /// Sample the card detect switch and report an insertion or removal once
+    /// `Config::card_detect_debounce` consecutive samples agree on it. A removal aborts any
+    /// ongoing operation, and the next card has to be initialized again.
+    pub fn poll_card_detect(&mut self) -> Option<CardEvent> {
+        let present = self.detect.card_present();
+        let event = self
+            .debounce
+            .update(present, self.config.card_detect_debounce);
+        if event == Some(CardEvent::Removed) {
+            self.abort();
+        }
+        event
+    }
+
+    /// Pick clock dividers from the frequency of the kernel clock of the host instead of the
+    /// dividers in the configuration.
+    pub fn with_kernel_clock(mut self, frequency: u32) -> Self {
+        self.kernel_clock = Some(frequency);
+        self
+    }
+
+    /// Time how long the card stays busy programming or erasing with `timer`, which returns the
+    /// value of a free-running millisecond counter such as one driven by SysTick. Otherwise the
+    /// time is estimated from the number of status polls, which is only accurate when
+    /// `CardHost::result` is polled in a tight loop.
+    pub fn with_timer(mut self, timer: fn() -> u32) -> Self {
+        self.timer = Some(timer);
+        self
+    }
+
+    /// The frequency the card is clocked at for data transfers, if the kernel clock frequency
+    /// is known. During identification, this is the identification clock.
+    pub fn card_clock(&self) -> Option<u32> {
+        self.kernel_clock
+            .map(|kernel_clock| H::card_clock(kernel_clock, self.clock_divider))
+    }
+
+    /// The smallest divider that clocks the card at no more than `max`, or `fallback` when the
+    /// kernel clock frequency is not known.
+    fn divider_for(&self, max: u32, fallback: u16) -> u16 {
+        match self.kernel_clock {
+            None => fallback,
+            Some(kernel_clock) => (1..=H::MAX_CLOCK_DIVIDER)
+                .find(|&divider| H::card_clock(kernel_clock, divider) <= max)
+                .unwrap_or(H::MAX_CLOCK_DIVIDER),
+        }
+    }
+
+    fn identification_divider(&self) -> u16 {
+        self.divider_for(IDENTIFICATION_CLOCK, IDENTIFICATION_CLOCK_DIVIDER)
+    }
+
+    /// The divider for data transfers in default speed mode, limited by the TRAN_SPEED of the
+    /// card.
+    fn data_divider(&self) -> u16 {
+        let tran_speed = self.csd.tran_speed().unwrap_or(DEFAULT_SPEED_CLOCK);
+        let max = self.config.data_clock.min(tran_speed);
+        self.divider_for(max, self.config.clock_divider)
+    }
+
+    /// The number of card clock cycles to wait for the data of a transfer, or for the card to
+    /// finish programming written data.
+    fn data_timeout(&self, direction: DataDirection) -> u32 {
+        let card_clock = match self.card_clock() {
+            Some(card_clock) => card_clock as u64,
+            None => return self.config.data_timeout,
+        };
+
+        // The read access time is TAAC plus NSAC, and cards may take a hundred times as long.
+        let max_read = card_clock * READ_TIMEOUT_MS / 1000;
+        let read = match self.csd.taac() {
+            Ok(taac) => {
+                let access = taac as u64 * card_clock / 1_000_000_000 + self.csd.nsac() as u64;
+                (100 * access).min(max_read)
+            }
+            Err(_) => max_read,
+        };
+
+        let timeout = match direction {
+            DataDirection::Write => {
+                let max_write = max_write_ms(&self.csd);
+                (read * self.csd.r2w_factor() as u64).min(card_clock * max_write / 1000)
+            }
+            _ => read,
+        };
+        timeout.min(u32::MAX as u64) as u32
+    }
+
+    /// How long the card may take to program written data in milliseconds, derived from the
+    /// CSD like the data timeout of writes.
+    fn write_timeout_ms(&self) -> u64 {
+        match self.card_clock() {
+            Some(card_clock) => {
+                let cycles = self.data_timeout(DataDirection::Write) as u64;
+                (cycles * 1000).div_ceil(card_clock as u64)
+            }
+            None => max_write_ms(&self.csd),
+        }
+    }
+
+    /// Program the data timeout for a transfer in `direction`.
+    fn set_data_timeout(&mut self, direction: DataDirection) {
+        let timeout = self.data_timeout(direction);
+        self.host
+            .configure(self.clock_divider, self.bus_width, timeout);
+    }
+
+    /// How long erasing the blocks from `start` to `end` may take in milliseconds, according to
+    /// the SD Status. Cards that do not report their erase timing get the write timeout for every
+    /// block.
+    fn erase_timeout_ms(&self, start: BlockIndex, end: BlockIndex, full: bool) -> u64 {
+        let status = &self.sd_status;
+        match (
+            status.au_size(),
+            status.erase_size(),
+            status.erase_timeout(),
+        ) {
+            _ if full && status.fule_support() => 1000,
+            (Ok(au_size), erase_size @ 1.., erase_timeout @ 1..) => {
+                let au_blocks = (au_size / BLOCK_SIZE) as u32;
+                let aus = ((end / au_blocks).saturating_sub(start / au_blocks) + 1) as u64;
+                let seconds = (erase_timeout as u64 * aus).div_ceil(erase_size as u64)
+                    + status.erase_offset() as u64;
+                seconds * 1000
+            }
+            _ => WRITE_TIMEOUT_MS * (end.saturating_sub(start) + 1) as u64,
+        }
+    }
+
+    /// Let the card stay busy for at most `ms` milliseconds from now on.
+    fn limit_busy(&mut self, ms: u64) {
+        self.busy_limit = match (self.timer, self.card_clock()) {
+            (Some(timer), _) => {
+                // Deadlines are compared as signed differences, so they are at most half the
+                // range of the timer away.
+                let ms = ms.min(i32::MAX as u64) as u32;
+                Some(BusyLimit::Deadline(timer().wrapping_add(ms)))
+            }
+            (None, Some(card_clock)) => Some(BusyLimit::Cycles(ms * card_clock as u64 / 1000)),
+            // The configured data timeout stands in for the write timeout.
+            (None, None) => Some(BusyLimit::Cycles(
+                self.config.data_timeout as u64 * ms / WRITE_TIMEOUT_MS,
+            )),
+        };
+    }
+
+    /// Whether the card has been busy for longer than its limit, after another poll found it
+    /// busy.
+    fn busy_timed_out(&mut self) -> bool {
+        match (&mut self.busy_limit, self.timer) {
+            (Some(BusyLimit::Deadline(deadline)), Some(timer)) => {
+                timer().wrapping_sub(*deadline) as i32 >= 0
+            }
+            (Some(BusyLimit::Cycles(0)), _) => true,
+            (Some(BusyLimit::Cycles(cycles)), _) => {
+                *cycles = cycles.saturating_sub(SEND_STATUS_CYCLES);
+                false
+            }
+            _ => false,
+        }
+    }
+
+    fn init_peri(&mut self, clock_divider: u16) {
+        self.host
+            .configure(clock_divider, self.bus_width, self.config.data_timeout);
+    }
+
+    pub fn host_status(&self) -> u32 {
+        self.host.status().0
+    }
+
+    /// The width of the data bus that is currently in use.
+    pub fn bus_width(&self) -> BusWidth {
+        self.bus_width
+    }
+
+    fn card_status(&mut self) -> Result<CardStatus, Error> {
+        self.init_peri(self.clock_divider);
+        let cmd = Command::SEND_STATUS;
+        Ok(CardStatus(
+            self.send(cmd as u8, cmd.response(), cmd.data_direction(), self.rca)?[0],
+        ))
+    }
+
+    /// Read the card status and turn any error it reports into an error.
+    fn checked_card_status(&mut self) -> Result<CardStatus, Error> {
+        let card_status = self.card_status()?;
+        match card_status.error() {
+            Some(e) => Err(e),
+            None => Ok(card_status),
+        }
+    }
+
+    fn check_operating_conditions(&mut self) -> Result<(), Error> {
+        match self.card_command(Command::SEND_IF_COND, SEND_IF_COND_PATTERN) {
+            Err(e) => Err(e),
+            Ok([received_pattern, ..]) => {
+                if received_pattern != SEND_IF_COND_PATTERN {
+                    Err(OperatingConditionsNotSupported)
+                } else {
+                    Ok(())
+                }
+            }
+        }
+    }
+
+    fn app_command(&mut self, cmd: AppCommand, arg: u32) -> Result<[u32; 4], Error> {
+        self.card_command(Command::APP_COMMAND, self.rca)?;
+        self.execute(cmd as u8, cmd.response(), cmd.data_direction(), arg)
+    }
+
+    /// Send a command like `start_command`, and wait until the card no longer signals busy after
+    /// an R1b response.
+    fn card_command(&mut self, cmd: Command, arg: u32) -> Result<[u32; 4], Error> {
+        let words = self.start_command(cmd, arg)?;
+        if cmd.response().is_busy() {
+            block!(self.busy_result())?;
+        }
+        Ok(words)
+    }
+
+    /// Send a command and check its response. The card may still signal busy after an R1b
+    /// response, which `busy_result` then waits out within the write timeout.
+    fn start_command(&mut self, cmd: Command, arg: u32) -> Result<[u32; 4], Error> {
+        let words = self.execute(cmd as u8, cmd.response(), cmd.data_direction(), arg)?;
+        if cmd.response().is_busy() {
+            self.limit_busy(self.write_timeout_ms());
+        }
+        Ok(words)
+    }
+
+    /// Poll the card once for the end of its busy signal, which follows written data, erases and
+    /// R1b responses. Fails with `Timeout` once the card has been busy for longer than its limit.
+    fn busy_result(&mut self) -> nb::Result<(), Error> {
+        let card_status = self.checked_card_status().map_err(Other)?;
+        if card_status.ready_for_data() && matches!(card_status.state(), CardState::Transmit) {
+            Ok(())
+        } else if self.busy_timed_out() {
+            Err(Other(Timeout))
+        } else {
+            Err(WouldBlock)
+        }
+    }
+
+    /// Send a command and wait for its response. Short responses are returned in the first word.
+    /// Errors in the card status of the response are turned into errors.
+    fn execute(
+        &mut self,
+        index: u8,
+        response: Response,
+        data: DataDirection,
+        arg: u32,
+    ) -> Result<[u32; 4], Error> {
+        let words = self.send(index, response, data, arg)?;
+        match response
+            .card_status(words[0])
+            .and_then(|s| s.command_error())
+        {
+            Some(e) => Err(e),
+            None => Ok(words),
+        }
+    }
+
+    /// Send a command and wait for its response without checking the card status it contains.
+    fn send(
+        &mut self,
+        index: u8,
+        response: Response,
+        data: DataDirection,
+        arg: u32,
+    ) -> Result<[u32; 4], Error> {
+        self.host.send_command(index, arg, response, data);
+        block!(self.check_command(response))?;
+        if response.has_index() && self.host.response_index() != index {
+            return Err(UnexpectedResponse);
+        }
+
+        Ok(self.host.response())
+    }
+
+    /// Read a register that the card sends as a single data block in response to an application
+    /// command.
+    fn read_register(&mut self, cmd: AppCommand, arg: u32, dest: &mut [u8]) -> Result<(), Error> {
+        let size = dest.len();
+        unsafe {
+            self.setup_read(dest, size);
+        }
+
+        if let Err(e) = self.app_command(cmd, arg) {
+            // The buffer may be on the stack of the caller, so the DMA must not outlive this call.
+            self.host.stop_dma();
+            return Err(e);
+        }
+        self.state = State::Reading;
+        block!(self.result())
+    }
+
+    /// Translate a block index into the address argument the card expects.
+    fn card_address(&self, block: BlockIndex) -> Result<u32, Error> {
+        self.card_version.card_address(block)
+    }
+
+    fn check_ready(&mut self) -> Result<(), Error> {
+        use State::*;
+        match self.state {
+            Uninitialized | Init1(_) => Err(Error::Uninitialized),
+            Ready => {
+                self.init_peri(self.clock_divider);
+                Ok(())
+            }
+            Reading | Writing | Programming | Erasing => Err(Error::Busy),
+        }
+    }
+
+    fn check_command(&mut self, response: Response) -> nb::Result<(), Error> {
+        let status = self.host.status();
+        if status.cmdact() {
+            return Err(WouldBlock);
+        }
+        self.host.clear_status();
+        if status.ccrcfail() {
+            // The response was received, but it has no valid CRC to check.
+            if response.has_crc() {
+                Err(Other(CRCFail))
+            } else {
+                Ok(())
+            }
+        } else if status.ctimeout() {
+            Err(Other(Timeout))
+        } else if (response != Response::None && !status.cmdrend())
+            || (response == Response::None && !status.cmdsent())
+        {
+            Err(Other(UnknownResult))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Run the next step of identifying and selecting the card.
+    fn identify(&mut self) -> nb::Result<(), Error> {
+        use State::*;
+        match self.state {
+            Reading | Writing | Programming | Erasing => {
+                self.reset();
+                Err(WouldBlock)
+            }
+
+            Uninitialized | Ready => {
+                self.rca = 0;
+                // Cards always start out in one bit mode at default speed.
+                self.bus_width = BusWidth::Bits1;
+                self.clock_divider = self.identification_divider();
+                self.init_peri(self.clock_divider);
+                // * -> idle
+                self.card_command(Command::GO_IDLE_STATE, 0)?;
+                // Determine card version.
+                let v2 = match self.check_operating_conditions() {
+                    Err(Timeout) => false,
+                    Ok(_) => true,
+                    Err(e) => return Err(Other(e)),
+                };
+
+                self.state = Init1(v2);
+                self.op_cond_polls = 0;
+                // Recurse once to start the next part.
+                self.identify()
+            }
+
+            Init1(v2) => {
+                self.init_peri(self.clock_divider);
+                // idle -> ready
+                let arg = 0x0010_0000 | (v2 as u32) << 30;
+                let result = match self.app_command(AppCommand::SD_SEND_OP_COND, arg) {
+                    Err(Timeout) if !v2 => Err(Other(NoCard)),
+                    Ok([result, ..]) if result >> 31 == 0 => {
+                        self.op_cond_polls += 1;
+                        if self.op_cond_polls <= self.config.op_cond_polls {
+                            Err(WouldBlock)
+                        } else {
+                            self.state = Uninitialized;
+                            Err(Other(Timeout))
+                        }
+                    }
+                    Ok([result, ..]) => Ok(result),
+                    Err(e) => {
+                        self.state = Uninitialized;
+                        Err(Other(e))
+                    }
+                }?;
+
+                self.state = Uninitialized;
+                let ccs = (result >> 30) & 1 != 0;
+                self.card_version = match (v2, ccs) {
+                    (false, _) => CardVersion::V1SC,
+                    (true, false) => CardVersion::V2SC,
+                    (true, true) => CardVersion::V2HC,
+                };
+
+                // ready -> ident
+                self.cid = Cid(self.card_command(Command::ALL_SEND_CID, 0)?);
+
+                // ident -> stby
+                let card_rca_status = self.card_command(Command::SEND_RELATIVE_ADDR, 0)?[0];
+                self.rca = card_rca_status & 0xffff_0000;
+                self.csd = Csd(self.card_command(Command::SEND_CSD, self.rca)?);
+                // Identification is done, so switch to the data clock the card supports.
+                self.clock_divider = self.data_divider();
+                self.init_peri(self.clock_divider);
+
+                // stby -> tran
+                self.card_command(Command::SELECT_CARD, self.rca)?;
+                // Standard capacity cards support other block lengths, so make sure all cards
+                // use the same one.
+                self.card_command(Command::SET_BLOCKLEN, BLOCK_SIZE as u32)?;
+
+                // The SCR tells whether the card supports SET_BLOCK_COUNT and a wide bus.
+                let mut scr = Scr([0; 8]);
+                self.state = Ready;
+                let result = self.read_register(AppCommand::SEND_SCR, 0, &mut scr.0);
+                self.state = Uninitialized;
+                result?;
+                self.scr = scr;
+
+                let bus_width = if self.config.negotiate_bus_width {
+                    match self.config.bus_width {
+                        BusWidth::Bits4 | BusWidth::Bits8
+                            if scr.supports_bus_width(BusWidth::Bits4) =>
+                        {
+                            BusWidth::Bits4
+                        }
+                        _ => BusWidth::Bits1,
+                    }
+                } else {
+                    self.config.bus_width
+                };
+
+                self.app_command(
+                    AppCommand::SET_BUS_WIDTH,
+                    match bus_width {
+                        BusWidth::Bits1 => 0,
+                        BusWidth::Bits4 => 2,
+                        BusWidth::Bits8 => return Err(Other(Error::Unsupported)),
+                    },
+                )?;
+                self.bus_width = bus_width;
+
+                // The erase timing in the SD Status does not change, so read it only once.
+                let mut sd_status = SDStatus([0; 64]);
+                self.state = Ready;
+                let result = self.read_register(AppCommand::SD_STATUS, self.rca, &mut sd_status.0);
+                if result.is_err() {
+                    self.state = Uninitialized;
+                }
+                result?;
+                self.sd_status = sd_status;
+                Ok(())
+            }
+        }
+    }
+
+    unsafe fn setup_read(&mut self, dest: &mut [u8], block_size: usize) {
+        let size = dest.len();
+        assert!(block_size.is_power_of_two() && block_size & 3 == 0 && block_size <= 1 << 14);
+        assert!(size & (block_size - 1) == 0 && size <= H::MAX_BLOCKS * BLOCK_SIZE);
+        self.set_data_timeout(DataDirection::Read);
+        self.host
+            .start_dma(dest.as_mut_ptr(), size, DataDirection::Read);
+        self.host.start_data(size, block_size, DataDirection::Read);
+    }
+
+    /// Take the next chunk of at most `H::MAX_BLOCKS` blocks off the remaining transfer.
+    unsafe fn next_chunk(&mut self) -> (*mut Block, usize, BlockIndex) {
+        let chunk = self.remaining;
+        let count = chunk.count.min(H::MAX_BLOCKS);
+        self.remaining = Chunks {
+            blocks: chunk.blocks.add(count),
+            count: chunk.count - count,
+            address: chunk.address + count as BlockIndex,
+        };
+        (chunk.blocks, count, chunk.address)
+    }
+
+    unsafe fn start_read_chunk(&mut self) -> Result<(), Error> {
+        let (blocks, count, address) = self.next_chunk();
+        let result = self.card_address(address).and_then(|address| {
+            // Not all cards support SET_BLOCK_COUNT, so only use it when it is needed. The others
+            // send blocks until they are stopped.
+            let command = match count {
+                1 => Command::READ_BLOCK,
+                _ if self.scr.cmd23_support() => {
+                    self.card_command(Command::SET_BLOCK_COUNT, count as u32)?;
+                    Command::READ_MULTIPLE_BLOCK
+                }
+                _ => Command::READ_MULTIPLE_BLOCK,
+            };
+            self.setup_read(
+                core::slice::from_raw_parts_mut(blocks as *mut u8, count * BLOCK_SIZE),
+                BLOCK_SIZE,
+            );
+            self.card_command(command, address)
+        });
+
+        match result {
+            Ok(_) => {
+                self.stop_transmission = count > 1 && !self.scr.cmd23_support();
+                self.state = State::Reading;
+                Ok(())
+            }
+
+            Err(e) => {
+                self.host.stop_dma();
+                self.remaining.count = 0;
+                self.state = State::Ready;
+                Err(e)
+            }
+        }
+    }
+
+    unsafe fn start_write_chunk(&mut self) -> Result<(), Error> {
+        let (blocks, count, address) = self.next_chunk();
+        let result = self.card_address(address).and_then(|address| match count {
+            1 => Ok((Command::WRITE_BLOCK, address)),
+            _ if self.scr.cmd23_support() => {
+                self.card_command(Command::SET_BLOCK_COUNT, count as u32)?;
+                Ok((Command::WRITE_MULTIPLE_BLOCK, address))
+            }
+            _ => Ok((Command::WRITE_MULTIPLE_BLOCK, address)),
+        });
+        let (command, address) = match result {
+            Ok(command) => command,
+            Err(e) => {
+                self.remaining.count = 0;
+                self.state = State::Ready;
+                return Err(e);
+            }
+        };
+
+        let len = count * BLOCK_SIZE;
+        self.set_data_timeout(DataDirection::Write);
+        self.host
+            .start_dma(blocks as *mut u8, len, DataDirection::Write);
+
+        // c. Set the address.
+        // d. Set the command register.
+        if let Err(e) = self.card_command(command, address) {
+            self.host.stop_dma();
+            self.remaining.count = 0;
+            self.state = State::Ready;
+            return Err(e);
+        }
+        self.stop_transmission = count > 1 && !self.scr.cmd23_support();
+        self.state = State::Writing;
+
+        self.host.start_data(len, BLOCK_SIZE, DataDirection::Write);
+
+        Ok(())
+    }
+}
+
+impl<H: HostController, D: CardDetect, W: WriteProtect, P: CardPower> CardHost
+    for Device<H, D, W, P>
+{
+    fn init_card(&mut self) -> nb::Result<(), Error> {
+        if matches!(self.state, State::Uninitialized | State::Ready) && !self.card_present() {
+            return Err(Other(NoCard));
+        }
+
+        let error = match self.identify() {
+            Err(Other(e)) => e,
+            result => {
+                if result.is_ok() {
+                    self.init_failures = 0;
+                }
+                return result;
+            }
+        };
+
+        // A card that keeps failing may be wedged, and only removing its power resets it. Try
+        // once more after a power cycle before giving up.
+        self.init_failures = self.init_failures.saturating_add(1);
+        let limit = self.config.power_cycle_after;
+        if limit == 0 || self.init_failures < limit {
+            return Err(Other(error));
+        }
+        if self.init_failures > limit {
+            self.init_failures = 0;
+            return Err(Other(error));
+        }
+
+        self.power_cycle()?;
+        Err(WouldBlock)
+    }
+
+    fn erase_card(&mut self) -> Result<(), Error> {
+        self.check_ready()?;
+        self.check_writable()?;
+        let card_size = self.card_size()?;
+        let timeout = self.erase_timeout_ms(0, card_size - 1, true);
+        self.card_command(Command::ERASE_WR_BLK_START, 0)?;
+        let end = self.card_address(card_size - 1)?;
+        self.card_command(Command::ERASE_WR_BLK_END, end)?;
+        // 2 means Full User area Logical Erase
+        self.start_command(Command::ERASE, 2)?;
+        self.limit_busy(timeout);
+        self.state = State::Erasing;
+        Ok(())
+    }
+
+    fn card_id(&mut self) -> Result<Cid, Error> {
+        match self.state {
+            State::Uninitialized => Err(Error::Uninitialized),
+            State::Init1(_) => Err(Error::Uninitialized),
+            _ => Ok(self.cid),
+        }
+    }
+
+    fn card_specific_data(&mut self) -> Result<Csd, Error> {
+        match self.state {
+            State::Uninitialized => Err(Error::Uninitialized),
+            State::Init1(_) => Err(Error::Uninitialized),
+            _ => Ok(self.csd),
+        }
+    }
+
+    fn card_size(&mut self) -> Result<BlockCount, Error> {
+        self.card_specific_data()?.capacity()
+    }
+
+    fn read_sd_status(&mut self) -> Result<SDStatus, Error> {
+        self.check_ready()?;
+        let mut result = SDStatus([0; 64]);
+        self.read_register(AppCommand::SD_STATUS, self.rca, &mut result.0)?;
+        Ok(result)
+    }
+
+    fn read_scr(&mut self) -> Result<Scr, Error> {
+        self.check_ready()?;
+        let mut result = Scr([0; 8]);
+        self.read_register(AppCommand::SEND_SCR, 0, &mut result.0)?;
+        Ok(result)
+    }
+
+    fn switch_function(
+        &mut self,
+        mode: SwitchMode,
+        group: u8,
+        function: u8,
+    ) -> Result<SwitchStatus, Error> {
+        self.check_ready()?;
+        let arg = mode.argument(group, function)?;
+        let mut status = SwitchStatus([0; 64]);
+        unsafe {
+            self.setup_read(&mut status.0, 64);
+        }
+
+        if let Err(e) = self.card_command(Command::SWITCH_FUNC, arg) {
+            // The status is on the stack, so the DMA must not outlive this call.
+            self.host.stop_dma();
+            return Err(e);
+        }
+        self.state = State::Reading;
+        block!(self.result())?;
+
+        if mode == SwitchMode::Switch
+            && group == ACCESS_MODE_GROUP
+            && status.selected(group) == Ok(function)
+        {
+            self.clock_divider = match function {
+                DEFAULT_SPEED => self.data_divider(),
+                _ => self.divider_for(
+                    self.config.high_speed_clock.min(HIGH_SPEED_CLOCK),
+                    self.config.high_speed_clock_divider,
+                ),
+            };
+            self.init_peri(self.clock_divider);
+        }
+
+        Ok(status)
+    }
+
+    fn erase(&mut self, start: BlockIndex, end: BlockIndex) -> Result<(), Error> {
+        self.check_ready()?;
+        self.check_writable()?;
+        let timeout = self.erase_timeout_ms(start, end, false);
+        let start = self.card_address(start)?;
+        let end = self.card_address(end)?;
+        self.card_command(Command::ERASE_WR_BLK_START, start)?;
+        self.card_command(Command::ERASE_WR_BLK_END, end)?;
+        self.start_command(Command::ERASE, 0)?;
+        self.limit_busy(timeout);
+        self.state = State::Erasing;
+        Ok(())
+    }
+
+    unsafe fn read_block(&mut self, block: &mut Block, address: BlockIndex) -> Result<(), Error> {
+        self.check_ready()?;
+        let address = self.card_address(address)?;
+        self.setup_read(block, BLOCK_SIZE);
+        match self.card_command(Command::READ_BLOCK, address) {
+            Ok(_) => {
+                self.state = State::Reading;
+                Ok(())
+            }
+
+            Err(e) => {
+                self.host.stop_dma();
+                Err(e)
+            }
+        }
+    }
+
+    unsafe fn read_blocks(
+        &mut self,
+        blocks: &mut [Block],
+        address: BlockIndex,
+    ) -> Result<(), Error> {
+        self.check_ready()?;
+        if blocks.is_empty() {
+            return Err(InvalidValue);
+        }
+
+        self.remaining = Chunks {
+            blocks: blocks.as_mut_ptr(),
+            count: blocks.len(),
+            address,
+        };
+        self.start_read_chunk()
+    }
+
+    unsafe fn write_blocks(&mut self, blocks: &[Block], address: BlockIndex) -> Result<(), Error> {
+        self.check_ready()?;
+        self.check_writable()?;
+        if blocks.is_empty() {
+            return Err(InvalidValue);
+        }
+
+        self.remaining = Chunks {
+            blocks: blocks.as_ptr() as *mut Block,
+            count: blocks.len(),
+            address,
+        };
+        self.start_write_chunk()
+    }
+
+    fn result(&mut self) -> nb::Result<(), Error> {
+        let status = self.host.status();
+        match self.state {
+            State::Uninitialized | State::Init1(_) => Err(Other(Error::Uninitialized)),
+            State::Ready => Err(Other(NoOperation)),
+            State::Reading if status.rxact() => Err(WouldBlock),
+            State::Writing if status.txact() => Err(WouldBlock),
+            State::Reading | State::Writing => Ok(()),
+            State::Programming => {
+                match self.busy_result() {
+                    Ok(()) => {}
+                    Err(WouldBlock) => return Err(WouldBlock),
+                    Err(Other(e)) => {
+                        self.remaining.count = 0;
+                        self.state = State::Ready;
+                        return Err(Other(e));
+                    }
+                }
+
+                if self.remaining.count == 0 {
+                    self.state = State::Ready;
+                    return Ok(());
+                }
+
+                unsafe { self.start_write_chunk() }?;
+                return Err(WouldBlock);
+            }
+            State::Erasing => {
+                let result = self.busy_result();
+                if !matches!(result, Err(WouldBlock)) {
+                    self.state = State::Ready;
+                }
+                return result;
+            }
+        }?;
+
+        self.host.stop_dma();
+        self.host.clear_status();
+        let state = self.state;
+        self.state = State::Ready;
+        let result = if status.dcrcfail() {
+            Err(CRCFail)
+        } else if status.dtimeout() {
+            Err(Timeout)
+        } else if status.rxoverr() {
+            Err(ReceiveOverrun)
+        } else if status.txunderr() {
+            Err(SendUnderrun)
+        } else if !status.dataend() || !status.dbckend() {
+            Err(UnknownResult)
+        } else {
+            Ok(())
+        };
+
+        // Transfers without a block count go on until the host stops them, also after an error.
+        let result = if core::mem::take(&mut self.stop_transmission) {
+            // Its response reports errors such as reading past the end of the card. The card stays
+            // busy programming written data, which the Programming state waits out.
+            let stop = match state {
+                State::Writing => self.start_command(Command::STOP_TRANSMISSION, 0),
+                _ => self.card_command(Command::STOP_TRANSMISSION, 0),
+            };
+            stop.and(result)
+        } else {
+            result
+        };
+
+        // Errors such as reading past the end of the card only show up in the card status.
+        let result = match result {
+            Err(e) => match self.card_status().map(|card_status| card_status.error()) {
+                Ok(Some(status_error)) => Err(status_error),
+                _ => Err(e),
+            },
+            Ok(()) => Ok(()),
+        };
+
+        if result.is_err() {
+            self.remaining.count = 0;
+            return result.map_err(Other);
+        }
+
+        // Continue with the next chunk of a long transfer. Writes also wait for the card to
+        // finish programming the data.
+        match state {
+            State::Reading if self.remaining.count == 0 => return Ok(()),
+            State::Reading => unsafe { self.start_read_chunk() }?,
+            _ => {
+                self.limit_busy(self.write_timeout_ms());
+                self.state = State::Programming;
+            }
+        }
+
+        Err(WouldBlock)
+    }
+}
@@ -0,0 +1,51 @@
+//! Volatile access to memory mapped registers, for the chips whose peripheral access crates this
+//! crate does not depend on.
+
+use core::cell::UnsafeCell;
+use core::ops::Deref;
+
+/// A 32 bit register.
+#[repr(transparent)]
+pub(crate) struct Reg(UnsafeCell<u32>);
+
+impl Reg {
+    pub(crate) fn read(&self) -> u32 {
+        unsafe { core::ptr::read_volatile(self.0.get()) }
+    }
+
+    pub(crate) fn write(&self, value: u32) {
+        unsafe { core::ptr::write_volatile(self.0.get(), value) }
+    }
+
+    #[cfg(feature = "stm32f4")]
+    pub(crate) fn modify(&self, f: impl FnOnce(u32) -> u32) {
+        self.write(f(self.read()));
+    }
+}
+
+/// The address of the registers of a peripheral from a peripheral access crate, which derefs to
+/// its register block. Owning the peripheral grants exclusive access to the registers.
+pub(crate) fn address<P: Deref>(peripheral: &P) -> usize {
+    (&**peripheral as *const P::Target).cast::<u8>() as usize
+}
+
+/// Memory standing in for the registers of a peripheral in unit tests.
+#[cfg(test)]
+pub(crate) struct MockRegisters(std::vec::Vec<Reg>);
+
+#[cfg(test)]
+impl MockRegisters {
+    /// Registers spanning `len` bytes, all zero.
+    pub(crate) fn new(len: usize) -> Self {
+        MockRegisters((0..len / 4).map(|_| Reg(UnsafeCell::new(0))).collect())
+    }
+
+    pub(crate) fn base(&self) -> usize {
+        self.0.as_ptr() as usize
+    }
+
+    /// The register at byte `offset`.
+    pub(crate) fn at(&self, offset: usize) -> &Reg {
+        &self.0[offset / 4]
+    }
+}
@@ -0,0 +1,168 @@
+use crate::{BusWidth, Error};
+
+/// The SD Configuration Register, which describes the optional features the card supports.
+#[derive(Copy, Clone)]
+pub struct Scr(pub(crate) [u8; 8]);
+
+impl core::fmt::Debug for Scr {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "Scr(")?;
+        write!(f, "scr_structure={}, ", self.scr_structure())?;
+        write!(f, "spec_version={:?}, ", self.spec_version())?;
+        write!(
+            f,
+            "data_stat_after_erase={}, ",
+            self.data_stat_after_erase()
+        )?;
+        write!(f, "sd_security={}, ", self.sd_security())?;
+        write!(f, "sd_bus_widths={:#x}, ", self.sd_bus_widths())?;
+        write!(f, "cmd20_support={:?}, ", self.cmd20_support())?;
+        write!(f, "cmd23_support={:?}, ", self.cmd23_support())?;
+        write!(f, "cmd48_support={:?}, ", self.cmd48_support())?;
+        write!(f, "cmd58_support={:?})", self.cmd58_support())?;
+        Ok(())
+    }
+}
+
+impl Scr {
+    /// Extract the bits `high..=low` of the register, numbered as in the specification.
+    fn bits(&self, high: usize, low: usize) -> u8 {
+        let value = u64::from_be_bytes(self.0) >> low;
+        (value & ((1 << (high - low + 1)) - 1)) as u8
+    }
+
+    fn bit(&self, bit: usize) -> bool {
+        self.bits(bit, bit) != 0
+    }
+
+    /// The version of the register layout (SCR_STRUCTURE).
+    pub fn scr_structure(&self) -> u8 {
+        self.bits(63, 60)
+    }
+
+    /// The physical layer specification version (SD_SPEC).
+    pub fn sd_spec(&self) -> u8 {
+        self.bits(59, 56)
+    }
+
+    /// The card supports version 3.00 or later of the specification (SD_SPEC3).
+    pub fn sd_spec3(&self) -> bool {
+        self.bit(47)
+    }
+
+    /// The card supports version 4.00 or later of the specification (SD_SPEC4).
+    pub fn sd_spec4(&self) -> bool {
+        self.bit(42)
+    }
+
+    /// The physical layer specification version for versions 5.00 and later (SD_SPECX).
+    pub fn sd_specx(&self) -> u8 {
+        self.bits(41, 38)
+    }
+
+    /// The supported physical layer specification as a major and minor version number, combined
+    /// from SD_SPEC, SD_SPEC3, SD_SPEC4 and SD_SPECX.
+    pub fn spec_version(&self) -> Result<(u8, u8), Error> {
+        match (
+            self.sd_spec(),
+            self.sd_spec3(),
+            self.sd_spec4(),
+            self.sd_specx(),
+        ) {
+            (0, false, false, 0) => Ok((1, 0)),
+            (1, false, false, 0) => Ok((1, 10)),
+            (2, false, false, 0) => Ok((2, 0)),
+            (2, true, false, 0) => Ok((3, 0)),
+            (2, true, true, 0) => Ok((4, 0)),
+            (2, true, _, x @ 1..=5) => Ok((x + 4, 0)),
+            _ => Err(Error::InvalidValue),
+        }
+    }
+
+    /// The value of erased data is all ones rather than all zeroes (DATA_STAT_AFTER_ERASE).
+    pub fn data_stat_after_erase(&self) -> bool {
+        self.bit(55)
+    }
+
+    /// The supported security specification version (SD_SECURITY).
+    pub fn sd_security(&self) -> u8 {
+        self.bits(54, 52)
+    }
+
+    /// The supported data bus widths as a bit mask (SD_BUS_WIDTHS).
+    pub fn sd_bus_widths(&self) -> u8 {
+        self.bits(51, 48)
+    }
+
+    /// The card supports the given data bus width.
+    pub fn supports_bus_width(&self, bus_width: BusWidth) -> bool {
+        match bus_width {
+            BusWidth::Bits1 => self.bit(48),
+            BusWidth::Bits4 => self.bit(50),
+            BusWidth::Bits8 => false,
+        }
+    }
+
+    /// The card supports the speed class control command (CMD20).
+    pub fn cmd20_support(&self) -> bool {
+        self.bit(32)
+    }
+
+    /// The card supports setting the block count for multiple block transfers (CMD23).
+    pub fn cmd23_support(&self) -> bool {
+        self.bit(33)
+    }
+
+    /// The card supports the extension register single block commands (CMD48 and CMD49).
+    pub fn cmd48_support(&self) -> bool {
+        self.bit(34)
+    }
+
+    /// The card supports the extension register multi-block commands (CMD58 and CMD59).
+    pub fn cmd58_support(&self) -> bool {
+        self.bit(35)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn version_3_card() {
+        let scr = Scr([0x02, 0x35, 0x80, 0x03, 0x00, 0x00, 0x00, 0x00]);
+        assert_eq!(scr.scr_structure(), 0);
+        assert_eq!(scr.sd_spec(), 2);
+        assert!(scr.sd_spec3());
+        assert!(!scr.sd_spec4());
+        assert_eq!(scr.sd_specx(), 0);
+        assert_eq!(scr.spec_version(), Ok((3, 0)));
+        assert!(!scr.data_stat_after_erase());
+        assert_eq!(scr.sd_security(), 3);
+        assert_eq!(scr.sd_bus_widths(), 0b0101);
+        assert!(scr.supports_bus_width(BusWidth::Bits1));
+        assert!(scr.supports_bus_width(BusWidth::Bits4));
+        assert!(!scr.supports_bus_width(BusWidth::Bits8));
+        assert!(scr.cmd20_support());
+        assert!(scr.cmd23_support());
+        assert!(!scr.cmd48_support());
+        assert!(!scr.cmd58_support());
+    }
+
+    #[test]
+    fn version_6_card() {
+        let scr = Scr([0x02, 0xb5, 0x84, 0x8f, 0x00, 0x00, 0x00, 0x00]);
+        assert!(scr.sd_spec4());
+        assert_eq!(scr.sd_specx(), 2);
+        assert_eq!(scr.spec_version(), Ok((6, 0)));
+        assert!(scr.data_stat_after_erase());
+        assert!(scr.cmd48_support());
+        assert!(scr.cmd58_support());
+
+        let scr = Scr([0x01, 0x01, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(scr.spec_version(), Ok((1, 10)));
+        assert!(!scr.supports_bus_width(BusWidth::Bits4));
+        let scr = Scr([0x03, 0x01, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(scr.spec_version(), Err(Error::InvalidValue));
+    }
+}
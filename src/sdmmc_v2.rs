@@ -0,0 +1,459 @@
+//! The second version of the STM32 SDMMC peripheral, which moves data with its own DMA (IDMA)
+//! instead of a DMA controller. The chip modules provide the constructors for their instances.
+
+use core::ops::Deref;
+
+use crate::register::{address, Reg};
+use crate::{
+    BusWidth, Config, DataDirection, Device, HostController, HostStatus, Response, BLOCK_SIZE,
+};
+
+/// All static flags in the ICR.
+const STATUS_ERROR_MASK: u32 = 0x1fe0_0fff;
+
+const POWER_ON: u32 = 3;
+const CLKCR_CLKDIV: u32 = 0x3ff;
+const CLKCR_PWRSAV: u32 = 1 << 12;
+const CLKCR_WIDBUS_SHIFT: u32 = 14;
+const CMDR_CMDTRANS: u32 = 1 << 6;
+const CMDR_WAITRESP_SHIFT: u32 = 8;
+const CMDR_CPSMEN: u32 = 1 << 12;
+const DCTRL_DTDIR: u32 = 1 << 1;
+const DCTRL_DBLOCKSIZE_SHIFT: u32 = 4;
+/// The flags of the STAR that have the same meaning and position in `HostStatus`.
+const STAR_COMMON: u32 = 0x0000_05ff;
+const STAR_DPSMACT: u32 = 1 << 12;
+const STAR_CPSMACT: u32 = 1 << 13;
+const STAR_IDMATE: u32 = 1 << 27;
+const IDMACTRL_IDMAEN: u32 = 1 << 0;
+
+/// Cortex-M7 cache maintenance by address: invalidate, clean, and clean and invalidate.
+const SCB_DCIMVAC: usize = 0xe000_ef5c;
+const SCB_DCCMVAC: usize = 0xe000_ef68;
+const SCB_DCCIMVAC: usize = 0xe000_ef70;
+const CACHE_LINE: usize = 32;
+
+#[repr(C)]
+struct SdmmcRegisters {
+    power: Reg,
+    clkcr: Reg,
+    argr: Reg,
+    cmdr: Reg,
+    respcmdr: Reg,
+    resp: [Reg; 4],
+    dtimer: Reg,
+    dlenr: Reg,
+    dctrl: Reg,
+    dcntr: Reg,
+    star: Reg,
+    icr: Reg,
+    maskr: Reg,
+    acktimer: Reg,
+    _reserved: [Reg; 3],
+    idmactrlr: Reg,
+    idmabsizer: Reg,
+    idmabase0r: Reg,
+    idmabase1r: Reg,
+}
+
+/// The memory the IDMA is transferring to or from.
+#[derive(Copy, Clone)]
+struct Buffer {
+    address: usize,
+    len: usize,
+    direction: DataDirection,
+    /// The data path has been programmed for this buffer.
+    data_path: bool,
+    /// The command that starts the transfer has been sent.
+    started: bool,
+}
+
+/// An instance of the SDMMC v2 peripheral.
+///
+/// The IDMA transfers each chunk in single buffer mode, straight to or from the whole buffer. So a
+/// chunk is only limited by the length of the data path, and nothing has to be serviced while it
+/// runs.
+pub struct Sdmmc<P> {
+    sdmmc: P,
+    base: usize,
+    /// Buffers have to be cleaned from or invalidated in the data cache of the CPU.
+    dcache: bool,
+    buffer: Option<Buffer>,
+}
+
+impl<P: Deref> Sdmmc<P> {
+    /// Take control of a peripheral of a peripheral access crate whose registers are at one of
+    /// `bases`, or return it if they are not.
+    pub(crate) fn with_peripheral(sdmmc: P, bases: &[usize], dcache: bool) -> Result<Self, P> {
+        let base = address(&sdmmc);
+        if !bases.contains(&base) {
+            return Err(sdmmc);
+        }
+
+        Ok(Sdmmc {
+            sdmmc,
+            base,
+            dcache,
+            buffer: None,
+        })
+    }
+
+    /// Get back the SDMMC peripheral.
+    pub fn free(self) -> P {
+        self.sdmmc
+    }
+}
+
+impl<P> Sdmmc<P> {
+    fn registers(&self) -> &SdmmcRegisters {
+        unsafe { &*(self.base as *const SdmmcRegisters) }
+    }
+
+    /// Program the length, block size and direction of the data path. The transfer starts with
+    /// the next command, which has CMDTRANS set.
+    fn program_data_path(&mut self, len: usize, block_size: usize, direction: DataDirection) {
+        let mut dctrl = block_size.trailing_zeros() << DCTRL_DBLOCKSIZE_SHIFT;
+        if direction == DataDirection::Read {
+            dctrl |= DCTRL_DTDIR;
+        }
+
+        let registers = self.registers();
+        registers.dlenr.write(len as u32);
+        registers.dctrl.write(dctrl);
+        if let Some(buffer) = self.buffer.as_mut() {
+            buffer.data_path = true;
+        }
+    }
+
+    /// Apply a cache maintenance operation to every cache line that overlaps the buffer.
+    fn maintain_cache(&self, operation: usize, buffer: &Buffer) {
+        if !self.dcache {
+            return;
+        }
+
+        let register = unsafe { &*(operation as *const Reg) };
+        barrier();
+        let mut line = buffer.address & !(CACHE_LINE - 1);
+        while line < buffer.address + buffer.len {
+            register.write(line as u32);
+            line += CACHE_LINE;
+        }
+        barrier();
+    }
+}
+
+/// Wait for memory accesses and cache maintenance to complete.
+fn barrier() {
+    #[cfg(target_arch = "arm")]
+    unsafe {
+        core::arch::asm!("dsb sy", "isb sy");
+    }
+}
+
+impl<P> HostController for Sdmmc<P> {
+    const MAX_CLOCK_DIVIDER: u16 = 2 * CLKCR_CLKDIV as u16;
+
+    /// The kernel clock is divided by twice CLKDIV, so odd dividers are rounded up.
+    fn card_clock(kernel_clock: u32, clock_divider: u16) -> u32 {
+        match clock_divider {
+            0 | 1 => kernel_clock,
+            divider => kernel_clock / (2 * (divider as u32).div_ceil(2)),
+        }
+    }
+
+    fn configure(&mut self, clock_divider: u16, bus_width: BusWidth, data_timeout: u32) {
+        let registers = self.registers();
+        // The card clock is the kernel clock divided by twice CLKDIV, or the kernel clock itself
+        // when CLKDIV is zero. Round up so the card is never clocked faster than requested.
+        let clkdiv = (clock_divider.min(Self::MAX_CLOCK_DIVIDER) as u32).div_ceil(2);
+        let mut clkcr = CLKCR_PWRSAV | clkdiv;
+        if clock_divider < 2 {
+            clkcr &= !CLKCR_CLKDIV;
+        }
+
+        clkcr |= match bus_width {
+            BusWidth::Bits1 => 0,
+            BusWidth::Bits4 => 1,
+            BusWidth::Bits8 => 2,
+        } << CLKCR_WIDBUS_SHIFT;
+
+        // The clock runs as soon as the card is powered.
+        registers.clkcr.write(clkcr);
+        registers.power.write(POWER_ON);
+
+        // Set the data timeout.
+        registers.dtimer.write(data_timeout);
+    }
+
+    fn send_command(&mut self, index: u8, arg: u32, response: Response, data: DataDirection) {
+        let waitresp = match response {
+            Response::None => 0,
+            _ if response.is_long() => 3,
+            // R3 has no CRC, so do not check it.
+            Response::R3 => 2,
+            _ => 1,
+        };
+        let mut cmdr = index as u32 | waitresp << CMDR_WAITRESP_SHIFT | CMDR_CPSMEN;
+
+        // The data path is started by the command, so writes have to program it now.
+        if data != DataDirection::None {
+            if let Some(buffer) = self.buffer {
+                if !buffer.data_path {
+                    self.program_data_path(buffer.len, BLOCK_SIZE, buffer.direction);
+                }
+                cmdr |= CMDR_CMDTRANS;
+                self.buffer = Some(Buffer {
+                    started: true,
+                    data_path: true,
+                    ..buffer
+                });
+            }
+        }
+
+        let registers = self.registers();
+        registers.argr.write(arg);
+        registers.cmdr.write(cmdr);
+    }
+
+    fn response_index(&self) -> u8 {
+        (self.registers().respcmdr.read() & 0x3f) as u8
+    }
+
+    fn response(&self) -> [u32; 4] {
+        let resp = &self.registers().resp;
+        [
+            resp[0].read(),
+            resp[1].read(),
+            resp[2].read(),
+            resp[3].read(),
+        ]
+    }
+
+    fn status(&self) -> HostStatus {
+        let star = self.registers().star.read();
+        let mut status = star & STAR_COMMON;
+        if star & STAR_CPSMACT != 0 {
+            status |= HostStatus::CMDACT;
+        }
+
+        let (active, dma_error) = match self.buffer.map(|buffer| buffer.direction) {
+            Some(DataDirection::Write) => (HostStatus::TXACT, HostStatus::TXUNDERR),
+            _ => (HostStatus::RXACT, HostStatus::RXOVERR),
+        };
+        if star & STAR_DPSMACT != 0 {
+            status |= active;
+        }
+        if star & STAR_IDMATE != 0 {
+            status |= dma_error;
+        }
+
+        HostStatus(status)
+    }
+
+    fn clear_status(&mut self) {
+        self.registers().icr.write(STATUS_ERROR_MASK);
+    }
+
+    fn start_data(&mut self, len: usize, block_size: usize, direction: DataDirection) {
+        // Writes are started by their command, which has already been sent at this point.
+        if !self.buffer.is_some_and(|buffer| buffer.started) {
+            self.program_data_path(len, block_size, direction);
+        }
+    }
+
+    unsafe fn start_dma(&mut self, buffer: *mut u8, len: usize, direction: DataDirection) {
+        self.stop_dma();
+        let buffer = Buffer {
+            address: buffer as usize,
+            len,
+            direction,
+            data_path: false,
+            started: false,
+        };
+
+        // Write back the data to send. Also write back dirty lines of a receive buffer, so they
+        // cannot be evicted over the received data later.
+        match direction {
+            DataDirection::Write => self.maintain_cache(SCB_DCCMVAC, &buffer),
+            _ => self.maintain_cache(SCB_DCCIMVAC, &buffer),
+        }
+
+        let registers = self.registers();
+        registers.idmabase0r.write(buffer.address as u32);
+        registers.idmactrlr.write(IDMACTRL_IDMAEN);
+        self.buffer = Some(buffer);
+    }
+
+    fn stop_dma(&mut self) {
+        self.registers().idmactrlr.write(0);
+        if let Some(buffer) = self.buffer.take() {
+            // Drop any lines of the received data the CPU loaded during the transfer.
+            if buffer.direction == DataDirection::Read {
+                self.maintain_cache(SCB_DCIMVAC, &buffer);
+            }
+        }
+    }
+
+    fn reset(&mut self) {
+        // The reset bits in the RCC belong to the HAL, so the power on values are restored
+        // through the registers of the peripheral instead.
+        self.stop_dma();
+        let registers = self.registers();
+        registers.power.write(0);
+        registers.clkcr.write(0);
+        registers.argr.write(0);
+        registers.cmdr.write(0);
+        registers.dtimer.write(0);
+        registers.dlenr.write(0);
+        registers.dctrl.write(0);
+        registers.maskr.write(0);
+        registers.acktimer.write(0);
+        registers.idmabsizer.write(0);
+        registers.idmabase0r.write(0);
+        registers.idmabase1r.write(0);
+        registers.icr.write(STATUS_ERROR_MASK);
+    }
+}
+
+impl<P> Device<Sdmmc<P>> {
+    /// Create a device for the peripheral, whose kernel clock runs at `kernel_clock` Hz. The card
+    /// clock dividers are derived from it, since the kernel clock of the SDMMC v2 may run much
+    /// faster than the 48 MHz the defaults in `Config` are meant for.
+    pub fn new(sdmmc: Sdmmc<P>, kernel_clock: u32, config: Config) -> Self {
+        Device::with_host(sdmmc, config).with_kernel_clock(kernel_clock)
+    }
+
+    /// Recycle the object to get back the SDMMC peripheral. Panics if an operation is still
+    /// ongoing.
+    pub fn free(mut self) -> Sdmmc<P> {
+        self.reset();
+        self.host
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::register::MockRegisters;
+
+    const CLKCR: usize = 0x04;
+    const CMDR: usize = 0x0c;
+    const DLENR: usize = 0x28;
+    const DCTRL: usize = 0x2c;
+    const STAR: usize = 0x34;
+    const IDMACTRLR: usize = 0x50;
+    const IDMABASE0R: usize = 0x58;
+
+    /// A peripheral of a peripheral access crate whose registers are at the given address. It is
+    /// never dereferenced.
+    pub(crate) struct Peripheral(pub(crate) usize);
+
+    impl Deref for Peripheral {
+        type Target = u32;
+
+        fn deref(&self) -> &u32 {
+            unsafe { &*(self.0 as *const u32) }
+        }
+    }
+
+    pub(crate) fn sdmmc(registers: &MockRegisters) -> Sdmmc<()> {
+        Sdmmc {
+            sdmmc: (),
+            base: registers.base(),
+            dcache: false,
+            buffer: None,
+        }
+    }
+
+    #[test]
+    fn clock_divider() {
+        let registers = MockRegisters::new(0x100);
+        let mut sdmmc = sdmmc(&registers);
+        sdmmc.configure(5, BusWidth::Bits4, 1000);
+        assert_eq!(registers.at(CLKCR).read(), CLKCR_PWRSAV | 3 | 1 << 14);
+        assert_eq!(Sdmmc::<()>::card_clock(48_000_000, 5), 8_000_000);
+        sdmmc.configure(1, BusWidth::Bits1, 1000);
+        assert_eq!(registers.at(CLKCR).read(), CLKCR_PWRSAV);
+        assert_eq!(registers.at(0x00).read(), POWER_ON);
+
+        // Dividers that do not fit CLKDIV get the largest one.
+        sdmmc.configure(4096, BusWidth::Bits1, 1000);
+        assert_eq!(registers.at(CLKCR).read(), CLKCR_PWRSAV | CLKCR_CLKDIV);
+    }
+
+    #[test]
+    fn single_buffer_read() {
+        let registers = MockRegisters::new(0x100);
+        let mut sdmmc = sdmmc(&registers);
+
+        // A chunk is as long as DATALENGTH allows.
+        let len = Sdmmc::<()>::MAX_BLOCKS * BLOCK_SIZE;
+        assert_eq!(len, 0x1ff_fe00);
+        unsafe { sdmmc.start_dma(0x2400_0000 as *mut u8, len, DataDirection::Read) };
+        sdmmc.start_data(len, BLOCK_SIZE, DataDirection::Read);
+        assert_eq!(registers.at(IDMABASE0R).read(), 0x2400_0000);
+        assert_eq!(registers.at(IDMACTRLR).read(), IDMACTRL_IDMAEN);
+        assert_eq!(registers.at(DLENR).read(), len as u32);
+        assert_eq!(registers.at(DCTRL).read(), DCTRL_DTDIR | 9 << 4);
+
+        sdmmc.send_command(18, 0, Response::R1, DataDirection::Read);
+        assert_eq!(
+            registers.at(CMDR).read(),
+            18 | 1 << 8 | CMDR_CPSMEN | CMDR_CMDTRANS
+        );
+        registers.at(STAR).write(STAR_DPSMACT);
+        assert!(sdmmc.status().rxact());
+        registers.at(STAR).write(STAR_IDMATE);
+        assert!(sdmmc.status().rxoverr());
+
+        sdmmc.stop_dma();
+        assert_eq!(registers.at(IDMACTRLR).read(), 0);
+    }
+
+    #[test]
+    fn register_read() {
+        let registers = MockRegisters::new(0x100);
+        let mut sdmmc = sdmmc(&registers);
+        unsafe { sdmmc.start_dma(0x2000_0000 as *mut u8, 8, DataDirection::Read) };
+        sdmmc.start_data(8, 8, DataDirection::Read);
+        assert_eq!(registers.at(IDMABASE0R).read(), 0x2000_0000);
+        assert_eq!(registers.at(DLENR).read(), 8);
+        assert_eq!(registers.at(DCTRL).read(), DCTRL_DTDIR | 3 << 4);
+    }
+
+    #[test]
+    fn write_starts_with_command() {
+        let registers = MockRegisters::new(0x100);
+        let mut sdmmc = sdmmc(&registers);
+        let len = 2 * BLOCK_SIZE;
+        unsafe { sdmmc.start_dma(0x2000_0000 as *mut u8, len, DataDirection::Write) };
+        sdmmc.send_command(25, 0, Response::R1, DataDirection::Write);
+        assert_eq!(registers.at(DLENR).read(), len as u32);
+        assert_eq!(registers.at(DCTRL).read(), 9 << 4);
+        assert_eq!(registers.at(IDMABASE0R).read(), 0x2000_0000);
+        assert_ne!(registers.at(CMDR).read() & CMDR_CMDTRANS, 0);
+
+        // The data path is not programmed again once the command has started the transfer.
+        registers.at(DLENR).write(0);
+        sdmmc.start_data(len, BLOCK_SIZE, DataDirection::Write);
+        assert_eq!(registers.at(DLENR).read(), 0);
+        registers.at(STAR).write(STAR_DPSMACT);
+        assert!(sdmmc.status().txact());
+
+        sdmmc.reset();
+        assert_eq!(registers.at(IDMACTRLR).read(), 0);
+        assert_eq!(registers.at(IDMABASE0R).read(), 0);
+        assert_eq!(registers.at(CLKCR).read(), 0);
+    }
+
+    #[test]
+    fn free_resets() {
+        let registers = MockRegisters::new(0x100);
+        let mut device = Device::new(sdmmc(&registers), 200_000_000, Config::default());
+        device.host_mut().configure(4, BusWidth::Bits4, 1000);
+        assert_ne!(registers.at(CLKCR).read(), 0);
+        device.free();
+        assert_eq!(registers.at(CLKCR).read(), 0);
+        assert_eq!(registers.at(0x00).read(), 0);
+    }
+}
@@ -0,0 +1,924 @@
+//! A behavioral model of an SD card, for running card hosts on a development machine.
+
+use crate::{
+    BusWidth, CardDetect, CardPower, CardState, CardVersion, DataDirection, Error, HostController,
+    HostStatus, Response, WriteProtect, BLOCK_SIZE,
+};
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::Arc;
+use std::vec;
+use std::vec::Vec;
+
+const OUT_OF_RANGE: u32 = 1 << 31;
+const ADDRESS_ERROR: u32 = 1 << 30;
+const BLOCK_LEN_ERROR: u32 = 1 << 29;
+const ERASE_SEQ_ERROR: u32 = 1 << 28;
+const ERASE_PARAM: u32 = 1 << 27;
+const WP_VIOLATION: u32 = 1 << 26;
+const ILLEGAL_COMMAND: u32 = 1 << 22;
+const ERROR: u32 = 1 << 19;
+const READY_FOR_DATA: u32 = 1 << 8;
+const APP_CMD: u32 = 1 << 5;
+/// Status bits that are cleared once they have been sent in a response.
+const CLEAR_ON_READ: u32 = 0xfdf9_8008;
+
+/// Set the bits `high..=low` of a register stored most significant word first, with the bits
+/// numbered as in the specification.
+fn set_bits(words: &mut [u32], high: usize, low: usize, value: u32) {
+    for bit in low..=high {
+        let word = &mut words[words.len() - 1 - bit / 32];
+        *word = *word & !(1 << (bit % 32)) | ((value >> (bit - low)) & 1) << (bit % 32);
+    }
+}
+
+/// The registers and timing of a simulated card.
+#[derive(Clone, Debug)]
+pub struct CardConfig {
+    /// Whether the card answers SEND_IF_COND and reports its capacity in the OCR.
+    pub version: CardVersion,
+    /// The operating conditions register, without the busy and capacity bits.
+    pub ocr: u32,
+    pub cid: [u32; 4],
+    pub csd: [u32; 4],
+    pub scr: [u8; 8],
+    pub sd_status: [u8; 64],
+    /// The relative card address the card publishes.
+    pub rca: u16,
+    /// The number of SD_SEND_OP_COND commands for which the card reports being busy.
+    pub init_polls: u32,
+    /// The number of SEND_STATUS commands for which the card stays busy after a write or erase.
+    pub busy_polls: u32,
+    /// The card supports high speed mode.
+    pub high_speed: bool,
+}
+
+impl CardConfig {
+    /// A version 1 standard capacity card. The number of blocks must be a multiple of 512 and at
+    /// most 1 GiB worth.
+    pub fn v1(blocks: u32) -> CardConfig {
+        assert!(blocks % 512 == 0 && blocks > 0 && blocks <= 4096 * 512);
+        let mut csd = Self::common_csd();
+        set_bits(&mut csd, 127, 126, 0);
+        set_bits(&mut csd, 73, 62, blocks / 512 - 1);
+        set_bits(&mut csd, 49, 47, 7);
+        let mut config = Self::common(CardVersion::V1SC, csd);
+        // SD 1.01, without CMD23 support.
+        config.scr[0] = 0x00;
+        config.scr[2] = 0x00;
+        config.scr[3] = 0x00;
+        config
+    }
+
+    /// A version 2 standard capacity card.
+    pub fn v2_sc(blocks: u32) -> CardConfig {
+        let mut config = Self::v1(blocks);
+        config.version = CardVersion::V2SC;
+        config.scr = Self::common_scr();
+        config
+    }
+
+    /// A version 2 high capacity card. The number of blocks must be a multiple of 1024.
+    pub fn v2_hc(blocks: u32) -> CardConfig {
+        assert!(blocks % 1024 == 0 && blocks > 0);
+        let mut csd = Self::common_csd();
+        set_bits(&mut csd, 127, 126, 1);
+        set_bits(&mut csd, 69, 48, blocks / 1024 - 1);
+        Self::common(CardVersion::V2HC, csd)
+    }
+
+    fn common(version: CardVersion, csd: [u32; 4]) -> CardConfig {
+        let mut cid = [0; 4];
+        set_bits(&mut cid, 127, 120, 0x03);
+        set_bits(&mut cid, 119, 104, u32::from(u16::from_be_bytes(*b"SD")));
+        for (i, &c) in b"SIMUL".iter().enumerate() {
+            set_bits(&mut cid, 103 - i * 8, 96 - i * 8, u32::from(c));
+        }
+        set_bits(&mut cid, 63, 56, 0x10);
+        set_bits(&mut cid, 55, 24, 0x1234_5678);
+        set_bits(&mut cid, 19, 8, 0x145);
+        set_bits(&mut cid, 0, 0, 1);
+
+        let mut sd_status = [0; 64];
+        // AU_SIZE of 4 MiB, ERASE_SIZE of 1 AU, ERASE_TIMEOUT of 1 s and ERASE_OFFSET of 1 s.
+        sd_status[10] = 0x90;
+        sd_status[12] = 0x01;
+        sd_status[13] = 0x05;
+
+        CardConfig {
+            version,
+            ocr: 0x00ff_8000,
+            cid,
+            csd,
+            scr: Self::common_scr(),
+            sd_status,
+            rca: 0xb368,
+            init_polls: 2,
+            busy_polls: 2,
+            high_speed: true,
+        }
+    }
+
+    fn common_csd() -> [u32; 4] {
+        let mut csd = [0; 4];
+        // 1 ms access time, 25 MHz, all mandatory command classes and switch.
+        set_bits(&mut csd, 119, 112, 0x0e);
+        set_bits(&mut csd, 102, 96, 0x32);
+        set_bits(&mut csd, 95, 84, 0x5b5);
+        set_bits(&mut csd, 83, 80, 9);
+        set_bits(&mut csd, 46, 46, 1);
+        set_bits(&mut csd, 45, 39, 0x7f);
+        set_bits(&mut csd, 28, 26, 2);
+        set_bits(&mut csd, 25, 22, 9);
+        set_bits(&mut csd, 0, 0, 1);
+        csd
+    }
+
+    fn common_scr() -> [u8; 8] {
+        // SD 3.0, 1 and 4 bit bus, CMD23 support.
+        [0x02, 0x35, 0x80, 0x02, 0, 0, 0, 0]
+    }
+
+    fn high_capacity(&self) -> bool {
+        matches!(self.version, CardVersion::V2HC)
+    }
+
+    fn capacity(&self) -> usize {
+        let csd = &self.csd;
+        let field = |high, low| crate::register_bits(csd, high, low) as usize;
+        match field(127, 126) {
+            0 => (field(73, 62) + 1) << (field(49, 47) + 2 + field(83, 80)),
+            _ => (field(69, 48) + 1) << 19,
+        }
+    }
+}
+
+/// A data transfer the card is waiting for.
+#[derive(Clone, Debug)]
+enum Pending {
+    None,
+    Read(Vec<u8>),
+    /// Blocks to write, starting at a byte offset.
+    Write(usize, usize),
+    /// Blocks to send from a byte offset until STOP_TRANSMISSION.
+    ReadStream(usize),
+    /// Blocks to receive at a byte offset until STOP_TRANSMISSION.
+    WriteStream(usize),
+}
+
+/// A simulated SD card, driven one command at a time.
+#[derive(Clone, Debug)]
+pub struct Card {
+    config: CardConfig,
+    data: Vec<u8>,
+    state: CardState,
+    status: u32,
+    app_command: bool,
+    rca: u16,
+    init_polls: u32,
+    busy: u32,
+    block_count: Option<u32>,
+    erase_range: (Option<usize>, Option<usize>),
+    bus_width: u8,
+    high_speed: bool,
+    pending: Pending,
+    /// The card stays busy in SD_SEND_OP_COND until it is power cycled.
+    wedged: bool,
+}
+
+impl Card {
+    /// Create a card in the idle state, with all data erased.
+    pub fn new(config: CardConfig) -> Card {
+        let capacity = config.capacity();
+        let mut card = Card {
+            data: vec![0; capacity],
+            config,
+            state: CardState::Idle,
+            status: 0,
+            app_command: false,
+            rca: 0,
+            init_polls: 0,
+            busy: 0,
+            block_count: None,
+            erase_range: (None, None),
+            bus_width: 0,
+            high_speed: false,
+            pending: Pending::None,
+            wedged: false,
+        };
+        card.reset();
+        card
+    }
+
+    pub fn config(&self) -> &CardConfig {
+        &self.config
+    }
+
+    /// The data stored on the card.
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    pub fn data_mut(&mut self) -> &mut [u8] {
+        &mut self.data
+    }
+
+    pub fn state(&self) -> CardState {
+        self.state
+    }
+
+    /// The width of the data bus selected with SET_BUS_WIDTH, in bits.
+    pub fn bus_width(&self) -> u8 {
+        self.bus_width
+    }
+
+    /// Whether the card was switched to high speed mode.
+    pub fn high_speed(&self) -> bool {
+        self.high_speed
+    }
+
+    /// Make the card hang in initialization until its power is removed.
+    pub fn wedge(&mut self) {
+        self.wedged = true;
+    }
+
+    /// Remove and restore the power of the card, which only keeps its data.
+    pub fn power_cycle(&mut self) {
+        self.wedged = false;
+        self.reset();
+    }
+
+    fn reset(&mut self) {
+        self.state = CardState::Idle;
+        self.status = 0;
+        self.app_command = false;
+        self.rca = 0;
+        self.init_polls = self.config.init_polls;
+        self.busy = 0;
+        self.block_count = None;
+        self.erase_range = (None, None);
+        self.bus_width = 1;
+        self.high_speed = false;
+        self.pending = Pending::None;
+    }
+
+    /// The card status as sent in R1 responses.
+    fn status(&mut self) -> u32 {
+        let mut status = self.status | (self.state as u32) << 9;
+        if self.app_command {
+            status |= APP_CMD;
+        }
+        if matches!(self.state, CardState::Transmit | CardState::Receive) {
+            status |= READY_FOR_DATA;
+        }
+
+        self.status &= !CLEAR_ON_READ;
+        status
+    }
+
+    fn r1(&mut self) -> Option<[u32; 4]> {
+        Some([self.status(), 0, 0, 0])
+    }
+
+    /// Reject a command without responding. The error shows up in the next status.
+    fn illegal(&mut self) -> Option<[u32; 4]> {
+        self.status |= ILLEGAL_COMMAND;
+        None
+    }
+
+    /// Convert a command argument to a byte offset, checking that `blocks` blocks fit.
+    fn address(&mut self, arg: u32, blocks: u32) -> Option<usize> {
+        let offset = if self.config.high_capacity() {
+            arg as usize * BLOCK_SIZE
+        } else if arg as usize % BLOCK_SIZE != 0 {
+            self.status |= ADDRESS_ERROR;
+            return None;
+        } else {
+            arg as usize
+        };
+
+        if offset + blocks as usize * BLOCK_SIZE > self.data.len() {
+            self.status |= OUT_OF_RANGE;
+            return None;
+        }
+
+        Some(offset)
+    }
+
+    fn write_protected(&self) -> bool {
+        crate::register_bits(&self.config.csd, 13, 12) != 0
+    }
+
+    /// Handle a command. Returns the response words, most significant word first for long
+    /// responses, or `None` if the card does not respond.
+    pub fn command(&mut self, index: u8, arg: u32) -> Option<[u32; 4]> {
+        let app_command = core::mem::replace(&mut self.app_command, false);
+        if index == 0 {
+            self.reset();
+            return None;
+        }
+
+        if app_command {
+            if let Some(response) = self.app_command(index, arg) {
+                return response;
+            }
+        }
+
+        use CardState::*;
+        match (index, self.state) {
+            (8, Idle) => match self.config.version {
+                CardVersion::V1SC => self.illegal(),
+                _ if arg >> 8 & 0xf == 1 => Some([arg & 0xfff, 0, 0, 0]),
+                _ => None,
+            },
+            (2, Ready) => {
+                self.state = Ident;
+                Some(self.config.cid)
+            }
+            (3, Ident) | (3, Standby) => {
+                self.rca = self.config.rca;
+                let response = self.r1()?[0];
+                self.state = Standby;
+                Some([
+                    (self.rca as u32) << 16
+                        | (response >> 8) & 0xc000
+                        | (response >> 6) & 0x2000
+                        | response & 0x1fff,
+                    0,
+                    0,
+                    0,
+                ])
+            }
+            (9, Standby) if arg >> 16 == self.rca as u32 => Some(self.config.csd),
+            (10, Standby) if arg >> 16 == self.rca as u32 => Some(self.config.cid),
+            (7, Standby) if arg >> 16 == self.rca as u32 => {
+                let response = self.r1();
+                self.state = Transmit;
+                response
+            }
+            (7, Transmit) | (7, Data) | (7, Program) if arg >> 16 != self.rca as u32 => {
+                let response = self.r1();
+                self.state = match self.state {
+                    Program => Disabled,
+                    _ => Standby,
+                };
+                response
+            }
+            (7, Disabled) if arg >> 16 == self.rca as u32 => {
+                let response = self.r1();
+                self.state = Program;
+                response
+            }
+            (13, Idle) | (13, Ready) | (13, Ident) => self.illegal(),
+            (13, _) if arg >> 16 == self.rca as u32 => {
+                if matches!(self.state, Program | Disabled) {
+                    if self.busy == 0 {
+                        self.state = match self.state {
+                            Program => Transmit,
+                            _ => Standby,
+                        };
+                    } else {
+                        self.busy -= 1;
+                    }
+                }
+                self.r1()
+            }
+            (13, _) => None,
+            (55, _) => {
+                self.app_command = true;
+                self.r1()
+            }
+            (6, Transmit) => {
+                let response = self.r1();
+                let status = self.switch(arg);
+                self.start_read(status);
+                response
+            }
+            (16, Transmit) => {
+                if arg as usize != BLOCK_SIZE && !self.config.high_capacity() {
+                    self.status |= BLOCK_LEN_ERROR;
+                }
+                self.r1()
+            }
+            (17, Transmit) | (18, Transmit) => {
+                let count = match index {
+                    17 => Some(1),
+                    _ => self.block_count.take(),
+                };
+                let offset = self.address(arg, count.unwrap_or(1));
+                let response = self.r1();
+                match (offset, count) {
+                    (Some(offset), Some(count)) => {
+                        let data = self.data[offset..offset + count as usize * BLOCK_SIZE].to_vec();
+                        self.start_read(data);
+                    }
+                    // Without a block count the card sends blocks until it is stopped.
+                    (Some(offset), None) => {
+                        self.state = Data;
+                        self.pending = Pending::ReadStream(offset);
+                    }
+                    _ => {}
+                }
+                response
+            }
+            (12, Data) => {
+                let response = self.r1();
+                self.pending = Pending::None;
+                self.state = Transmit;
+                response
+            }
+            (12, Receive) => {
+                let response = self.r1();
+                self.pending = Pending::None;
+                self.start_busy();
+                response
+            }
+            (23, Transmit) => {
+                if self.config.scr[3] & 0x02 == 0 {
+                    return self.illegal();
+                }
+                self.block_count = Some(arg);
+                self.r1()
+            }
+            (24, Transmit) | (25, Transmit) => {
+                let count = match index {
+                    24 => Some(1),
+                    _ => self.block_count.take(),
+                };
+                if self.write_protected() {
+                    self.status |= WP_VIOLATION;
+                    return self.r1();
+                }
+                let offset = self.address(arg, count.unwrap_or(1));
+                let response = self.r1();
+                if let Some(offset) = offset {
+                    self.state = Receive;
+                    self.pending = match count {
+                        Some(count) => Pending::Write(offset, count as usize * BLOCK_SIZE),
+                        // Without a block count the card takes blocks until it is stopped.
+                        None => Pending::WriteStream(offset),
+                    };
+                }
+                response
+            }
+            (32, Transmit) => {
+                self.erase_range = (self.address(arg, 1), None);
+                self.r1()
+            }
+            (33, Transmit) => {
+                if self.erase_range.0.is_none() {
+                    self.status |= ERASE_SEQ_ERROR;
+                } else {
+                    self.erase_range.1 = self.address(arg, 1);
+                }
+                self.r1()
+            }
+            (38, Transmit) => {
+                let response = self.r1();
+                match core::mem::take(&mut self.erase_range) {
+                    (Some(start), Some(end)) if start <= end => {
+                        let value = match self.config.scr[1] & 0x80 {
+                            0 => 0x00,
+                            _ => 0xff,
+                        };
+                        self.data[start..end + BLOCK_SIZE].fill(value);
+                        self.start_busy();
+                    }
+                    (Some(_), Some(_)) => self.status |= ERASE_PARAM,
+                    _ => self.status |= ERASE_SEQ_ERROR,
+                }
+                response
+            }
+            _ => self.illegal(),
+        }
+    }
+
+    /// Handle an application specific command. Returns `None` if the command is not one, in which
+    /// case it is handled as a regular command.
+    fn app_command(&mut self, index: u8, arg: u32) -> Option<Option<[u32; 4]>> {
+        use CardState::*;
+        // Responses to application specific commands have APP_CMD set.
+        self.app_command = true;
+        let response = match (index, self.state) {
+            (41, Idle) => {
+                let high_capacity = self.config.high_capacity();
+                let high_capacity_host = arg & 1 << 30 != 0;
+                let ocr = match high_capacity {
+                    true => self.config.ocr | 1 << 30,
+                    false => self.config.ocr,
+                };
+                let ready = if arg & 0x00ff_ffff == 0 {
+                    // Only an inquiry.
+                    false
+                } else if self.wedged {
+                    false
+                } else if self.init_polls > 0 {
+                    self.init_polls -= 1;
+                    false
+                } else {
+                    // High capacity cards stay busy for hosts that do not support them.
+                    !high_capacity || high_capacity_host
+                };
+
+                if ready {
+                    self.state = Ready;
+                    Some([ocr | 1 << 31, 0, 0, 0])
+                } else {
+                    Some([ocr, 0, 0, 0])
+                }
+            }
+            (6, Transmit) => {
+                match arg & 3 {
+                    0 => self.bus_width = 1,
+                    2 => self.bus_width = 4,
+                    _ => self.status |= ERROR,
+                }
+                self.r1()
+            }
+            (13, Transmit) => {
+                let response = self.r1();
+                let mut status = self.config.sd_status;
+                status[0] = status[0] & 0x3f | if self.bus_width == 4 { 0x80 } else { 0 };
+                self.start_read(status.to_vec());
+                response
+            }
+            (22, Transmit) | (23, Transmit) => self.r1(),
+            (51, Transmit) => {
+                let response = self.r1();
+                self.start_read(self.config.scr.to_vec());
+                response
+            }
+            _ => {
+                self.app_command = false;
+                return None;
+            }
+        };
+
+        self.app_command = false;
+        Some(response)
+    }
+
+    /// Build the status data structure of a SWITCH_FUNC command and perform the switch.
+    fn switch(&mut self, arg: u32) -> Vec<u8> {
+        let mut status = vec![0; 64];
+        status[0..2].copy_from_slice(&100u16.to_be_bytes());
+        // All groups support their default function.
+        for group in 0..6 {
+            status[12 - 2 * group + 1] = 1;
+        }
+        if self.config.high_speed {
+            status[13] |= 2;
+        }
+
+        // Only the access mode group has functions other than the default.
+        let selected = match arg & 0xf {
+            0xf => self.high_speed as u8,
+            0 => 0,
+            1 if self.config.high_speed => 1,
+            _ => 0xf,
+        };
+        status[16] = selected;
+        status[17] = 1;
+        if arg >> 31 == 1 && selected != 0xf {
+            self.high_speed = selected == 1;
+        }
+
+        status
+    }
+
+    fn start_read(&mut self, data: Vec<u8>) {
+        self.state = CardState::Data;
+        self.pending = Pending::Read(data);
+    }
+
+    fn start_busy(&mut self) {
+        self.state = CardState::Program;
+        self.busy = self.config.busy_polls;
+    }
+
+    /// Whether the card has data to send.
+    pub fn has_read_data(&self) -> bool {
+        matches!(self.pending, Pending::Read(_) | Pending::ReadStream(_))
+    }
+
+    /// Take the data the card sends in response to the last command, if any. A card that sends
+    /// blocks until it is stopped sends `len` bytes, or as many as it has left.
+    pub fn read_data(&mut self, len: usize) -> Option<Vec<u8>> {
+        match core::mem::replace(&mut self.pending, Pending::None) {
+            Pending::Read(data) => {
+                if let CardState::Data = self.state {
+                    self.state = CardState::Transmit;
+                }
+                Some(data)
+            }
+            Pending::ReadStream(offset) => {
+                let end = offset + len;
+                if end > self.data.len() {
+                    self.status |= OUT_OF_RANGE;
+                }
+                Some(self.data[offset..end.min(self.data.len())].to_vec())
+            }
+            pending => {
+                self.pending = pending;
+                None
+            }
+        }
+    }
+
+    /// Send data to the card after a write command. Returns whether the card accepted it.
+    pub fn write_data(&mut self, data: &[u8]) -> bool {
+        match core::mem::replace(&mut self.pending, Pending::None) {
+            Pending::Write(offset, len) if data.len() == len => {
+                self.data[offset..offset + len].copy_from_slice(data);
+                self.start_busy();
+                true
+            }
+            Pending::WriteStream(offset) if offset + data.len() <= self.data.len() => {
+                self.data[offset..offset + data.len()].copy_from_slice(data);
+                true
+            }
+            pending => {
+                self.pending = pending;
+                false
+            }
+        }
+    }
+}
+
+/// Status flags that report the outcome of a command.
+const COMMAND_FLAGS: u32 =
+    HostStatus::CCRCFAIL | HostStatus::CTIMEOUT | HostStatus::CMDREND | HostStatus::CMDSENT;
+
+/// A card slot behind a mocked host controller, for running a `Device` against a simulated card.
+/// Data transfers finish as soon as the command that starts them is handled.
+pub struct Simulator {
+    card: Option<Card>,
+    present: Arc<AtomicBool>,
+    write_protect: Arc<AtomicBool>,
+    power: Arc<Power>,
+    /// The number of times the power was switched off when the card last saw it.
+    power_offs: u32,
+    status: u32,
+    response: [u32; 4],
+    response_index: u8,
+    dma: Option<(*mut u8, usize)>,
+    data: Option<(usize, DataDirection)>,
+    clock_divider: u16,
+    bus_width: BusWidth,
+    data_timeout: u32,
+    /// The data timeout when the last transfer started.
+    transfer_timeout: u32,
+}
+
+impl Simulator {
+    pub fn new(card: Option<Card>) -> Simulator {
+        Simulator {
+            present: Arc::new(AtomicBool::new(card.is_some())),
+            write_protect: Arc::new(AtomicBool::new(false)),
+            power: Arc::new(Power {
+                on: AtomicBool::new(true),
+                offs: AtomicU32::new(0),
+            }),
+            power_offs: 0,
+            card,
+            status: 0,
+            response: [0; 4],
+            response_index: 0,
+            dma: None,
+            data: None,
+            clock_divider: 0,
+            bus_width: BusWidth::Bits1,
+            data_timeout: 0,
+            transfer_timeout: 0,
+        }
+    }
+
+    /// Put a card in the slot, replacing any card that was in it.
+    pub fn insert(&mut self, card: Card) -> Option<Card> {
+        self.present.store(true, Ordering::Relaxed);
+        self.card.replace(card)
+    }
+
+    /// Take the card out of the slot.
+    pub fn remove(&mut self) -> Option<Card> {
+        self.present.store(false, Ordering::Relaxed);
+        self.card.take()
+    }
+
+    /// A card detect switch that follows the cards put in and taken out of the slot.
+    pub fn card_detect(&self) -> Switch {
+        Switch(self.present.clone())
+    }
+
+    /// A write protect switch that follows `set_write_protect`.
+    pub fn write_protect(&self) -> Switch {
+        Switch(self.write_protect.clone())
+    }
+
+    /// A switch for the power of the slot.
+    pub fn power(&self) -> PowerSwitch {
+        PowerSwitch(self.power.clone())
+    }
+
+    /// Slide the write protect tab of the card in the slot.
+    pub fn set_write_protect(&mut self, protected: bool) {
+        self.write_protect.store(protected, Ordering::Relaxed);
+    }
+
+    pub fn card(&self) -> Option<&Card> {
+        self.card.as_ref()
+    }
+
+    pub fn card_mut(&mut self) -> Option<&mut Card> {
+        self.card.as_mut()
+    }
+
+    /// The clock divider the host last configured.
+    pub fn clock_divider(&self) -> u16 {
+        self.clock_divider
+    }
+
+    /// The width of the data bus the host last configured.
+    pub fn bus_width(&self) -> BusWidth {
+        self.bus_width
+    }
+
+    /// Whether the DMA is enabled.
+    pub fn dma_enabled(&self) -> bool {
+        self.dma.is_some()
+    }
+
+    /// The data timeout in card clock cycles the last transfer started with.
+    pub fn transfer_timeout(&self) -> u32 {
+        self.transfer_timeout
+    }
+
+    /// The card in the slot if it is powered. A card that lost power since the last command
+    /// starts over.
+    fn powered_card(&mut self) -> Option<&mut Card> {
+        if !self.power.on.load(Ordering::Relaxed) {
+            return None;
+        }
+
+        let card = self.card.as_mut()?;
+        let offs = self.power.offs.load(Ordering::Relaxed);
+        if offs != self.power_offs {
+            self.power_offs = offs;
+            card.power_cycle();
+        }
+        Some(card)
+    }
+
+    /// The data path waits for a card that has no data to send.
+    fn waiting_for_data(&self) -> bool {
+        self.data.map(|(_, direction)| direction) == Some(DataDirection::Read)
+            && !self.card.as_ref().is_some_and(Card::has_read_data)
+    }
+
+    /// Move data between the DMA buffer and the card once the data path is enabled and the
+    /// response to the command has been handled.
+    fn run_data(&mut self) {
+        if self.status & COMMAND_FLAGS != 0 {
+            return;
+        }
+
+        let (buffer, dma_len, len, direction) = match (self.dma, self.data) {
+            (Some((buffer, dma_len)), Some((len, direction))) => (buffer, dma_len, len, direction),
+            _ => return,
+        };
+        // The DMA buffer is valid until the DMA is stopped.
+        let buffer = unsafe { std::slice::from_raw_parts_mut(buffer, dma_len.min(len)) };
+        let done = match (direction, self.card.as_mut()) {
+            (DataDirection::Read, Some(card)) => match card.read_data(len) {
+                Some(data) if data.len() == len => {
+                    buffer.copy_from_slice(&data[..buffer.len()]);
+                    true
+                }
+                Some(_) => false,
+                None => return,
+            },
+            (DataDirection::Write, Some(card)) => card.write_data(buffer),
+            _ => false,
+        };
+
+        self.data = None;
+        self.status |= match done {
+            true => HostStatus::DATAEND | HostStatus::DBCKEND,
+            false => HostStatus::DTIMEOUT,
+        };
+    }
+}
+
+/// The card detect or write protect switch of a `Simulator` slot.
+pub struct Switch(Arc<AtomicBool>);
+
+impl CardDetect for Switch {
+    fn card_present(&mut self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+impl WriteProtect for Switch {
+    fn write_protected(&mut self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+struct Power {
+    on: AtomicBool,
+    /// The number of times the power was switched off.
+    offs: AtomicU32,
+}
+
+/// The power switch of a `Simulator` slot. Delays return immediately.
+pub struct PowerSwitch(Arc<Power>);
+
+impl CardPower for PowerSwitch {
+    fn set_power(&mut self, on: bool) -> Result<(), Error> {
+        if !on && self.0.on.swap(false, Ordering::Relaxed) {
+            self.0.offs.fetch_add(1, Ordering::Relaxed);
+        }
+        self.0.on.store(on, Ordering::Relaxed);
+        Ok(())
+    }
+
+    fn delay_ms(&mut self, _ms: u32) {}
+}
+
+impl HostController for Simulator {
+    /// Transfers are split up like on the STM32L4x6.
+    const MAX_BLOCKS: usize = 0xffff / (BLOCK_SIZE / 4);
+
+    fn configure(&mut self, clock_divider: u16, bus_width: BusWidth, data_timeout: u32) {
+        self.clock_divider = clock_divider;
+        self.bus_width = bus_width;
+        self.data_timeout = data_timeout;
+    }
+
+    fn send_command(&mut self, index: u8, arg: u32, response: Response, _data: DataDirection) {
+        let words = self
+            .powered_card()
+            .and_then(|card| card.command(index, arg));
+        self.response_index = match response {
+            Response::R2 | Response::R3 => 0x3f,
+            _ => index,
+        };
+        self.status = match (response, words) {
+            (Response::None, _) => HostStatus::CMDSENT,
+            (_, None) => HostStatus::CTIMEOUT,
+            // The host checks a CRC that R3 responses do not have.
+            (Response::R3, Some(_)) => HostStatus::CCRCFAIL,
+            (_, Some(_)) => HostStatus::CMDREND,
+        };
+        self.response = words.unwrap_or([0; 4]);
+    }
+
+    fn response_index(&self) -> u8 {
+        self.response_index
+    }
+
+    fn response(&self) -> [u32; 4] {
+        self.response
+    }
+
+    fn status(&self) -> HostStatus {
+        // Reads time out once the command is done without the card sending anything.
+        if self.status & COMMAND_FLAGS == 0 && self.waiting_for_data() {
+            return HostStatus(self.status | HostStatus::DTIMEOUT);
+        }
+
+        HostStatus(self.status)
+    }
+
+    fn clear_status(&mut self) {
+        self.status = 0;
+        self.run_data();
+    }
+
+    fn start_data(&mut self, len: usize, _block_size: usize, direction: DataDirection) {
+        self.data = Some((len, direction));
+        self.transfer_timeout = self.data_timeout;
+        self.run_data();
+    }
+
+    unsafe fn start_dma(&mut self, buffer: *mut u8, len: usize, _direction: DataDirection) {
+        self.dma = Some((buffer, len));
+    }
+
+    fn stop_dma(&mut self) {
+        self.dma = None;
+        self.data = None;
+    }
+
+    fn reset(&mut self) {
+        // Only the controller is reset, the slot and its switches stay as they are.
+        self.status = 0;
+        self.response = [0; 4];
+        self.response_index = 0;
+        self.dma = None;
+        self.data = None;
+        self.clock_divider = 0;
+        self.bus_width = BusWidth::Bits1;
+        self.data_timeout = 0;
+        self.transfer_timeout = 0;
+    }
+}
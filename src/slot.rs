@@ -0,0 +1,218 @@
+//! The switches of a card slot, which tell whether a card is in it and whether it may be written,
+//! and turn its power on and off.
+
+use crate::Error;
+#[cfg(feature = "embedded-hal")]
+use embedded_hal::blocking::delay::DelayMs;
+#[cfg(feature = "embedded-hal")]
+use embedded_hal::digital::v2::{InputPin, OutputPin};
+
+/// A switch that reports whether a card is in the slot.
+pub trait CardDetect {
+    /// Sample the switch, without any debouncing.
+    fn card_present(&mut self) -> bool;
+}
+
+/// Used when the slot has no card detect switch. A card is always assumed to be present.
+impl CardDetect for () {
+    fn card_present(&mut self) -> bool {
+        true
+    }
+}
+
+/// A switch that reports whether the card may be written to, like the write protect tab of a
+/// full size card.
+pub trait WriteProtect {
+    /// Sample the switch.
+    fn write_protected(&mut self) -> bool;
+}
+
+/// Used when the slot has no write protect switch.
+impl WriteProtect for () {
+    fn write_protected(&mut self) -> bool {
+        false
+    }
+}
+
+/// A switch that turns the power of the card on and off, and a way to wait for the supply to
+/// settle.
+pub trait CardPower {
+    /// Switch the power of the card on or off.
+    fn set_power(&mut self, on: bool) -> Result<(), Error>;
+
+    /// Wait for at least `ms` milliseconds.
+    fn delay_ms(&mut self, ms: u32);
+}
+
+/// Used when the card is powered all the time.
+impl CardPower for () {
+    fn set_power(&mut self, _on: bool) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn delay_ms(&mut self, _ms: u32) {}
+}
+
+/// A change reported by a card detect switch.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum CardEvent {
+    Inserted,
+    Removed,
+}
+
+/// A card detect or write protect switch connected to an input pin. It is active when a card is
+/// present or write protected. A pin that cannot be read reports no card and protection.
+#[cfg(feature = "embedded-hal")]
+pub struct SwitchPin<P> {
+    pin: P,
+    active_low: bool,
+}
+
+#[cfg(feature = "embedded-hal")]
+impl<P: InputPin> SwitchPin<P> {
+    /// A switch that pulls the pin low when it is active.
+    pub fn active_low(pin: P) -> Self {
+        SwitchPin {
+            pin,
+            active_low: true,
+        }
+    }
+
+    /// A switch that pulls the pin high when it is active.
+    pub fn active_high(pin: P) -> Self {
+        SwitchPin {
+            pin,
+            active_low: false,
+        }
+    }
+
+    pub fn free(self) -> P {
+        self.pin
+    }
+
+    fn active(&self) -> Option<bool> {
+        self.pin.is_low().ok().map(|low| low == self.active_low)
+    }
+}
+
+#[cfg(feature = "embedded-hal")]
+impl<P: InputPin> CardDetect for SwitchPin<P> {
+    fn card_present(&mut self) -> bool {
+        self.active().unwrap_or(false)
+    }
+}
+
+#[cfg(feature = "embedded-hal")]
+impl<P: InputPin> WriteProtect for SwitchPin<P> {
+    fn write_protected(&mut self) -> bool {
+        self.active().unwrap_or(true)
+    }
+}
+
+/// A power switch for the card controlled by an output pin, with a delay to time the power
+/// sequence.
+#[cfg(feature = "embedded-hal")]
+pub struct PowerPin<P, DELAY> {
+    pin: P,
+    delay: DELAY,
+    active_low: bool,
+}
+
+#[cfg(feature = "embedded-hal")]
+impl<P: OutputPin, DELAY: DelayMs<u32>> PowerPin<P, DELAY> {
+    /// A switch that powers the card while the pin is low.
+    pub fn active_low(pin: P, delay: DELAY) -> Self {
+        PowerPin {
+            pin,
+            delay,
+            active_low: true,
+        }
+    }
+
+    /// A switch that powers the card while the pin is high.
+    pub fn active_high(pin: P, delay: DELAY) -> Self {
+        PowerPin {
+            pin,
+            delay,
+            active_low: false,
+        }
+    }
+
+    pub fn free(self) -> (P, DELAY) {
+        (self.pin, self.delay)
+    }
+}
+
+#[cfg(feature = "embedded-hal")]
+impl<P: OutputPin, DELAY: DelayMs<u32>> CardPower for PowerPin<P, DELAY> {
+    fn set_power(&mut self, on: bool) -> Result<(), Error> {
+        let result = match on != self.active_low {
+            true => self.pin.set_high(),
+            false => self.pin.set_low(),
+        };
+        result.map_err(|_| Error::BusError)
+    }
+
+    fn delay_ms(&mut self, ms: u32) {
+        self.delay.delay_ms(ms);
+    }
+}
+
+/// The write protection of a card, from the switch of its slot and the bits in its CSD.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct WriteProtection {
+    /// The write protect switch of the slot is set.
+    pub switch: bool,
+    /// The card is temporarily write protected (TMP_WRITE_PROTECT).
+    pub temporary: bool,
+    /// The card is permanently write protected (PERM_WRITE_PROTECT).
+    pub permanent: bool,
+}
+
+impl WriteProtection {
+    /// Any kind of protection is active, so the card must not be written to.
+    pub fn is_protected(&self) -> bool {
+        self.switch || self.temporary || self.permanent
+    }
+}
+
+/// The debounced state of a card detect switch.
+#[derive(Copy, Clone, Debug)]
+pub(crate) struct Debounce {
+    present: bool,
+    /// The number of consecutive samples that disagreed with `present`.
+    changed: u8,
+}
+
+impl Debounce {
+    pub(crate) fn new(present: bool) -> Self {
+        Debounce {
+            present,
+            changed: 0,
+        }
+    }
+
+    pub(crate) fn present(&self) -> bool {
+        self.present
+    }
+
+    /// Take a sample and report a change once `samples` consecutive samples agree on it.
+    pub(crate) fn update(&mut self, present: bool, samples: u8) -> Option<CardEvent> {
+        if present == self.present {
+            self.changed = 0;
+            return None;
+        }
+
+        self.changed += 1;
+        if self.changed < samples {
+            return None;
+        }
+
+        self.present = present;
+        self.changed = 0;
+        Some(match present {
+            true => CardEvent::Inserted,
+            false => CardEvent::Removed,
+        })
+    }
+}
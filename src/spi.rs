@@ -0,0 +1,617 @@
+//! A card host that talks to the card in SPI mode, for boards that wire the card socket to a SPI
+//! peripheral instead of an SD host controller.
+
+use crate::protocol::{max_write_ms, SEND_IF_COND_PATTERN};
+use crate::Error::*;
+use crate::{
+    AppCommand, Block, BlockCount, BlockIndex, CardHost, CardVersion, Cid, Command, Csd, Error,
+    SDStatus, Scr, SwitchMode, SwitchStatus, BLOCK_SIZE,
+};
+use embedded_hal::blocking::spi;
+use embedded_hal::digital::v2::OutputPin;
+use nb::Error::{Other, WouldBlock};
+
+/// The number of bytes to wait for a response. Cards respond within eight.
+const RESPONSE_POLLS: usize = 16;
+/// The number of bytes to wait for the start of a data block.
+const TOKEN_POLLS: usize = 0x1_0000;
+/// The number of times to send GO_IDLE_STATE before giving up.
+const IDLE_ATTEMPTS: usize = 8;
+/// The fastest clock a card supports in default speed mode.
+const DEFAULT_CLOCK: u32 = 25_000_000;
+
+const R1_IDLE: u8 = 1 << 0;
+const R1_ERRORS: [(u8, Error); 5] = [
+    (1 << 6, OutOfRange),
+    (1 << 5, AddressMisaligned),
+    (1 << 4, EraseSequenceError),
+    (1 << 3, CommandCRCFail),
+    (1 << 2, IllegalCommand),
+];
+/// The errors in the second byte of an R2 response, in order of precedence.
+const R2_ERRORS: [(u8, Error); 7] = [
+    (1 << 7, OutOfRange),
+    (1 << 6, InvalidEraseSelection),
+    (1 << 5, WriteProtectViolation),
+    (1 << 4, ECCFailed),
+    (1 << 3, CardControllerError),
+    (1 << 2, CardError),
+    (1 << 1, LockUnlockFailed),
+];
+/// The errors in a data error token, in order of precedence.
+const DATA_ERRORS: [(u8, Error); 4] = [
+    (1 << 3, OutOfRange),
+    (1 << 2, ECCFailed),
+    (1 << 1, CardControllerError),
+    (1 << 0, CardError),
+];
+
+const START_BLOCK: u8 = 0xfe;
+const START_MULTIPLE_BLOCK: u8 = 0xfc;
+const STOP_TRANSMISSION: u8 = 0xfd;
+
+/// The CRC7 that protects commands and responses.
+fn crc7(data: &[u8]) -> u8 {
+    let mut crc = 0u8;
+    for &byte in data {
+        for bit in (0..8).rev() {
+            let feedback = (byte >> bit) & 1 ^ crc >> 6;
+            crc = (crc << 1) & 0x7f;
+            if feedback != 0 {
+                crc ^= 0x09;
+            }
+        }
+    }
+
+    crc
+}
+
+/// The CRC16 that protects data blocks.
+fn crc16(data: &[u8]) -> u16 {
+    let mut crc = 0u16;
+    for &byte in data {
+        crc ^= (byte as u16) << 8;
+        for _ in 0..8 {
+            crc = match crc & 0x8000 {
+                0 => crc << 1,
+                _ => crc << 1 ^ 0x1021,
+            };
+        }
+    }
+
+    crc
+}
+
+fn first_error(errors: &[(u8, Error)], bits: u8) -> Result<(), Error> {
+    match errors.iter().find(|&&(bit, _)| bits & bit != 0) {
+        Some(&(_, error)) => Err(error),
+        None => Ok(()),
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+enum State {
+    Uninitialized,
+    Init1(bool),
+    Ready,
+    /// A read has finished, its result has not been requested yet.
+    Done,
+    /// Waiting for the card to finish programming or erasing.
+    Busy,
+}
+
+/// A card connected to a SPI bus. The card is selected by driving `CS` low.
+///
+/// The bus must run at 100 to 400 kHz until `init_card` has finished, after which it may be raised
+/// to the speed the card supports through `spi_mut`. Transfers are performed by the processor, so
+/// reads and writes have finished by the time they return, apart from the card programming the
+/// data, which `result` waits for.
+///
+/// Waiting for a busy card gives up with a timeout after the number of bytes that the write or
+/// erase timeout of the card lasts at the clock frequency set by `set_clock`.
+pub struct SpiDevice<SPI, CS> {
+    spi: SPI,
+    cs: CS,
+    clock: u32,
+    state: State,
+    /// The number of times `result` may still find the card busy.
+    busy_polls: u64,
+    card_version: CardVersion,
+    csd: Csd,
+    cid: Cid,
+}
+
+impl<SPI, CS> SpiDevice<SPI, CS>
+where
+    SPI: spi::Transfer<u8>,
+    CS: OutputPin,
+{
+    pub fn new(spi: SPI, cs: CS) -> Self {
+        SpiDevice {
+            spi,
+            cs,
+            clock: DEFAULT_CLOCK,
+            state: State::Uninitialized,
+            busy_polls: 0,
+            card_version: CardVersion::V1SC,
+            csd: Csd([0; 4]),
+            cid: Cid([0; 4]),
+        }
+    }
+
+    /// Recycle the object to get back the SPI bus and chip select pin.
+    pub fn free(self) -> (SPI, CS) {
+        (self.spi, self.cs)
+    }
+
+    /// The SPI bus, for changing its speed.
+    pub fn spi_mut(&mut self) -> &mut SPI {
+        &mut self.spi
+    }
+
+    /// Set the frequency of the SPI clock, from which the number of times to poll a busy card is
+    /// derived. Defaults to 25 MHz, the fastest clock a card supports in default speed mode, so
+    /// timeouts only take longer than necessary at slower clocks.
+    pub fn set_clock(&mut self, clock: u32) {
+        self.clock = clock;
+    }
+
+    fn exchange(&mut self, byte: u8) -> Result<u8, Error> {
+        let mut buffer = [byte];
+        self.spi.transfer(&mut buffer).map_err(|_| BusError)?;
+        Ok(buffer[0])
+    }
+
+    fn receive(&mut self, dest: &mut [u8]) -> Result<(), Error> {
+        for byte in dest.iter_mut() {
+            *byte = 0xff;
+        }
+
+        self.spi.transfer(dest).map_err(|_| BusError)?;
+        Ok(())
+    }
+
+    fn send(&mut self, data: &[u8]) -> Result<(), Error> {
+        let mut buffer = [0; 64];
+        for chunk in data.chunks(buffer.len()) {
+            let buffer = &mut buffer[..chunk.len()];
+            buffer.copy_from_slice(chunk);
+            self.spi.transfer(buffer).map_err(|_| BusError)?;
+        }
+
+        Ok(())
+    }
+
+    fn select(&mut self) -> Result<(), Error> {
+        self.cs.set_low().map_err(|_| BusError)
+    }
+
+    /// Release the card. The card only releases the data out line on the next clock edge, so an
+    /// extra byte is clocked out.
+    fn deselect(&mut self) -> Result<(), Error> {
+        self.cs.set_high().map_err(|_| BusError)?;
+        self.exchange(0xff)?;
+        Ok(())
+    }
+
+    /// Select the card, run `f` and release the card again, even if `f` fails.
+    fn selected<T>(&mut self, f: impl FnOnce(&mut Self) -> Result<T, Error>) -> Result<T, Error> {
+        self.select()?;
+        let result = f(self);
+        self.deselect()?;
+        result
+    }
+
+    /// Send a command and return the R1 response. Errors in the response are returned as such.
+    fn command(&mut self, index: u8, arg: u32) -> Result<u8, Error> {
+        let mut frame = [0; 6];
+        frame[0] = 0x40 | index;
+        frame[1..5].copy_from_slice(&arg.to_be_bytes());
+        frame[5] = crc7(&frame[..5]) << 1 | 1;
+        self.exchange(0xff)?;
+        self.send(&frame)?;
+        if index == Command::STOP_TRANSMISSION as u8 {
+            // Skip the stuff byte.
+            self.exchange(0xff)?;
+        }
+
+        for _ in 0..RESPONSE_POLLS {
+            let r1 = self.exchange(0xff)?;
+            if r1 & 0x80 == 0 {
+                first_error(&R1_ERRORS, r1)?;
+                return Ok(r1);
+            }
+        }
+
+        Err(Timeout)
+    }
+
+    fn card_command(&mut self, cmd: Command, arg: u32) -> Result<u8, Error> {
+        self.command(cmd as u8, arg)
+    }
+
+    fn app_command(&mut self, cmd: AppCommand, arg: u32) -> Result<u8, Error> {
+        self.card_command(Command::APP_COMMAND, 0)?;
+        self.command(cmd as u8, arg)
+    }
+
+    /// Read the second byte of an R2 response and check it for errors.
+    fn check_r2(&mut self) -> Result<(), Error> {
+        let status = self.exchange(0xff)?;
+        first_error(&R2_ERRORS, status)
+    }
+
+    fn check_status(&mut self) -> Result<(), Error> {
+        self.card_command(Command::SEND_STATUS, 0)?;
+        self.check_r2()
+    }
+
+    /// Read the 32 bits that follow the R1 byte of R3 and R7 responses.
+    fn read_u32(&mut self) -> Result<u32, Error> {
+        let mut bytes = [0; 4];
+        self.receive(&mut bytes)?;
+        Ok(u32::from_be_bytes(bytes))
+    }
+
+    fn read_data(&mut self, dest: &mut [u8]) -> Result<(), Error> {
+        let mut token = 0xff;
+        for _ in 0..TOKEN_POLLS {
+            token = self.exchange(0xff)?;
+            if token != 0xff {
+                break;
+            }
+        }
+
+        match token {
+            START_BLOCK => {}
+            0xff => return Err(Timeout),
+            token if token & 0xf0 == 0 => {
+                first_error(&DATA_ERRORS, token)?;
+                return Err(UnknownResult);
+            }
+            _ => return Err(UnexpectedResponse),
+        }
+
+        self.receive(dest)?;
+        let mut crc = [0; 2];
+        self.receive(&mut crc)?;
+        if u16::from_be_bytes(crc) != crc16(dest) {
+            return Err(CRCFail);
+        }
+
+        Ok(())
+    }
+
+    fn read_register(&mut self, dest: &mut [u32; 4]) -> Result<(), Error> {
+        let mut bytes = [0; 16];
+        self.read_data(&mut bytes)?;
+        for (word, bytes) in dest.iter_mut().zip(bytes.chunks(4)) {
+            *word = u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+        }
+
+        Ok(())
+    }
+
+    /// Send a data block and check the data response token. The card is busy programming the data
+    /// afterwards.
+    fn write_data(&mut self, token: u8, data: &[u8]) -> Result<(), Error> {
+        self.exchange(token)?;
+        self.send(data)?;
+        self.send(&crc16(data).to_be_bytes())?;
+        match self.exchange(0xff)? & 0x1f {
+            0x05 => Ok(()),
+            0x0b => Err(CRCFail),
+            0x0d => Err(CardError),
+            _ => Err(UnexpectedResponse),
+        }
+    }
+
+    /// The number of bytes exchanged in `ms` milliseconds.
+    fn polls(&self, ms: u64) -> u64 {
+        (ms * self.clock as u64 / 1000 / 8).max(1)
+    }
+
+    /// The number of bytes the card may signal busy for while it programs `blocks` blocks.
+    fn write_polls(&self, blocks: u64) -> u64 {
+        self.polls(max_write_ms(&self.csd) * blocks)
+    }
+
+    fn wait_not_busy(&mut self) -> Result<(), Error> {
+        for _ in 0..self.write_polls(1) {
+            if self.exchange(0xff)? == 0xff {
+                return Ok(());
+            }
+        }
+
+        Err(Timeout)
+    }
+
+    fn card_address(&self, block: BlockIndex) -> Result<u32, Error> {
+        self.card_version.card_address(block)
+    }
+
+    fn check_ready(&mut self) -> Result<(), Error> {
+        match self.state {
+            State::Uninitialized | State::Init1(_) => Err(Uninitialized),
+            State::Done | State::Busy => Err(Busy),
+            State::Ready => Ok(()),
+        }
+    }
+
+    /// Reset the card to SPI mode and determine whether it is version 2 or later.
+    fn go_idle(&mut self) -> Result<bool, Error> {
+        // The card needs at least 74 clock cycles with chip select high to power up.
+        self.cs.set_high().map_err(|_| BusError)?;
+        self.send(&[0xff; 10])?;
+
+        self.selected(|host| {
+            let mut result = Err(NoCard);
+            for _ in 0..IDLE_ATTEMPTS {
+                result = match host.card_command(Command::GO_IDLE_STATE, 0) {
+                    Ok(R1_IDLE) => Ok(()),
+                    Ok(_) => Err(UnexpectedResponse),
+                    Err(Timeout) => Err(NoCard),
+                    Err(e) => Err(e),
+                };
+                if result.is_ok() {
+                    break;
+                }
+            }
+            result?;
+
+            let v2 = match host.card_command(Command::SEND_IF_COND, SEND_IF_COND_PATTERN) {
+                Ok(_) => {
+                    if host.read_u32()? & 0xfff != SEND_IF_COND_PATTERN {
+                        return Err(OperatingConditionsNotSupported);
+                    }
+                    true
+                }
+                Err(IllegalCommand) => false,
+                Err(e) => return Err(e),
+            };
+
+            host.card_command(Command::CRC_ON_OFF, 1)?;
+            Ok(v2)
+        })
+    }
+
+    /// Poll the card until it has left the idle state, then read its registers.
+    fn identify(&mut self, v2: bool) -> nb::Result<(), Error> {
+        let r1 = self.app_command(AppCommand::SD_SEND_OP_COND, (v2 as u32) << 30)?;
+        if r1 & R1_IDLE != 0 {
+            return Err(WouldBlock);
+        }
+
+        self.card_version = if v2 {
+            self.card_command(Command::READ_OCR, 0)?;
+            match self.read_u32()? >> 30 & 1 {
+                0 => CardVersion::V2SC,
+                _ => CardVersion::V2HC,
+            }
+        } else {
+            CardVersion::V1SC
+        };
+
+        let mut csd = [0; 4];
+        self.card_command(Command::SEND_CSD, 0)?;
+        self.read_register(&mut csd)?;
+        self.csd = Csd(csd);
+
+        let mut cid = [0; 4];
+        self.card_command(Command::SEND_CID, 0)?;
+        self.read_register(&mut cid)?;
+        self.cid = Cid(cid);
+
+        // Standard capacity cards support other block lengths, so make sure all cards use the
+        // same one.
+        self.card_command(Command::SET_BLOCKLEN, BLOCK_SIZE as u32)?;
+        Ok(())
+    }
+}
+
+impl<SPI, CS> CardHost for SpiDevice<SPI, CS>
+where
+    SPI: spi::Transfer<u8>,
+    CS: OutputPin,
+{
+    fn init_card(&mut self) -> nb::Result<(), Error> {
+        match self.state {
+            State::Init1(v2) => {
+                self.select()?;
+                let result = self.identify(v2);
+                self.deselect()?;
+                match result {
+                    Ok(()) => self.state = State::Ready,
+                    Err(Other(_)) => self.state = State::Uninitialized,
+                    Err(WouldBlock) => {}
+                }
+                result
+            }
+
+            _ => {
+                self.state = State::Uninitialized;
+                let v2 = self.go_idle()?;
+                self.state = State::Init1(v2);
+                // Recurse once to start the next part.
+                self.init_card()
+            }
+        }
+    }
+
+    fn card_id(&mut self) -> Result<Cid, Error> {
+        match self.state {
+            State::Uninitialized | State::Init1(_) => Err(Uninitialized),
+            _ => Ok(self.cid),
+        }
+    }
+
+    fn card_specific_data(&mut self) -> Result<Csd, Error> {
+        match self.state {
+            State::Uninitialized | State::Init1(_) => Err(Uninitialized),
+            _ => Ok(self.csd),
+        }
+    }
+
+    fn card_size(&mut self) -> Result<BlockCount, Error> {
+        self.card_specific_data()?.capacity()
+    }
+
+    fn erase_card(&mut self) -> Result<(), Error> {
+        let card_size = self.card_size()?;
+        self.erase(0, card_size - 1)
+    }
+
+    fn read_sd_status(&mut self) -> Result<SDStatus, Error> {
+        self.check_ready()?;
+        let mut result = SDStatus([0; 64]);
+        self.selected(|host| {
+            host.app_command(AppCommand::SD_STATUS, 0)?;
+            host.check_r2()?;
+            host.read_data(&mut result.0)
+        })?;
+        Ok(result)
+    }
+
+    fn read_scr(&mut self) -> Result<Scr, Error> {
+        self.check_ready()?;
+        let mut result = Scr([0; 8]);
+        self.selected(|host| {
+            host.app_command(AppCommand::SEND_SCR, 0)?;
+            host.read_data(&mut result.0)
+        })?;
+        Ok(result)
+    }
+
+    fn switch_function(
+        &mut self,
+        mode: SwitchMode,
+        group: u8,
+        function: u8,
+    ) -> Result<SwitchStatus, Error> {
+        self.check_ready()?;
+        let arg = mode.argument(group, function)?;
+        let mut status = SwitchStatus([0; 64]);
+        self.selected(|host| {
+            host.card_command(Command::SWITCH_FUNC, arg)?;
+            host.read_data(&mut status.0)
+        })?;
+        Ok(status)
+    }
+
+    fn erase(&mut self, start: BlockIndex, end: BlockIndex) -> Result<(), Error> {
+        self.check_ready()?;
+        // Cards take at most the write timeout for every erased block.
+        let busy_polls = self.write_polls(end.saturating_sub(start) as u64 + 1);
+        let start = self.card_address(start)?;
+        let end = self.card_address(end)?;
+        self.selected(|host| {
+            host.card_command(Command::ERASE_WR_BLK_START, start)?;
+            host.card_command(Command::ERASE_WR_BLK_END, end)?;
+            host.card_command(Command::ERASE, 0)?;
+            Ok(())
+        })?;
+        self.busy_polls = busy_polls;
+        self.state = State::Busy;
+        Ok(())
+    }
+
+    unsafe fn read_block(&mut self, block: &mut Block, address: BlockIndex) -> Result<(), Error> {
+        self.check_ready()?;
+        let address = self.card_address(address)?;
+        self.selected(|host| {
+            host.card_command(Command::READ_BLOCK, address)?;
+            host.read_data(block)
+        })?;
+        self.state = State::Done;
+        Ok(())
+    }
+
+    unsafe fn read_blocks(
+        &mut self,
+        blocks: &mut [Block],
+        address: BlockIndex,
+    ) -> Result<(), Error> {
+        self.check_ready()?;
+        if blocks.is_empty() {
+            return Err(InvalidValue);
+        }
+
+        let address = self.card_address(address)?;
+        self.selected(|host| {
+            host.card_command(Command::READ_MULTIPLE_BLOCK, address)?;
+            let result = blocks
+                .iter_mut()
+                .try_for_each(|block| host.read_data(block));
+            host.card_command(Command::STOP_TRANSMISSION, 0)?;
+            host.wait_not_busy()?;
+            result
+        })?;
+        self.state = State::Done;
+        Ok(())
+    }
+
+    unsafe fn write_blocks(&mut self, blocks: &[Block], address: BlockIndex) -> Result<(), Error> {
+        self.check_ready()?;
+        let address = self.card_address(address)?;
+        self.selected(|host| match blocks {
+            [] => Err(InvalidValue),
+            [block] => {
+                host.card_command(Command::WRITE_BLOCK, address)?;
+                host.write_data(START_BLOCK, block)
+            }
+            blocks => {
+                host.card_command(Command::WRITE_MULTIPLE_BLOCK, address)?;
+                for block in blocks {
+                    let result = host.write_data(START_MULTIPLE_BLOCK, block);
+                    host.wait_not_busy()?;
+                    if let Err(e) = result {
+                        host.exchange(STOP_TRANSMISSION)?;
+                        return Err(e);
+                    }
+                }
+                host.exchange(STOP_TRANSMISSION)?;
+                // The card starts signalling busy a byte after the stop token.
+                host.exchange(0xff)?;
+                Ok(())
+            }
+        })?;
+        self.busy_polls = self.write_polls(1);
+        self.state = State::Busy;
+        Ok(())
+    }
+
+    fn result(&mut self) -> nb::Result<(), Error> {
+        match self.state {
+            State::Uninitialized | State::Init1(_) => Err(Other(Uninitialized)),
+            State::Ready => Err(Other(NoOperation)),
+            State::Done => {
+                self.state = State::Ready;
+                Ok(())
+            }
+            State::Busy => {
+                let result = self.selected(|host| match host.exchange(0xff)? {
+                    0xff => host.check_status().map(Some),
+                    _ => Ok(None),
+                });
+                match result {
+                    Ok(None) if self.busy_polls > 1 => {
+                        self.busy_polls -= 1;
+                        Err(WouldBlock)
+                    }
+                    Ok(None) => {
+                        self.state = State::Ready;
+                        Err(Other(Timeout))
+                    }
+                    Ok(Some(())) => {
+                        self.state = State::Ready;
+                        Ok(())
+                    }
+                    Err(e) => {
+                        self.state = State::Ready;
+                        Err(Other(e))
+                    }
+                }
+            }
+        }
+    }
+}
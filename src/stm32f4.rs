@@ -0,0 +1,412 @@
+//! The SDIO peripheral of the STM32F2 and STM32F4 families, with DMA2 stream 3 or 6.
+
+use core::ops::Deref;
+
+use crate::register::{address, Reg};
+use crate::{
+    BusWidth, Config, DataDirection, Device, HostController, HostStatus, Response, BLOCK_SIZE,
+};
+
+const SDIO_BASE: usize = 0x4001_2c00;
+const SDIO_FIFO_OFFSET: usize = 0x80;
+const DMA2_BASE: usize = 0x4002_6400;
+/// All static flags in the ICR, including STBITERR.
+const STATUS_ERROR_MASK: u32 = 0x0000_07ff;
+
+const CLKCR_CLKDIV: u32 = 0xff;
+const CLKCR_CLKEN: u32 = 1 << 8;
+const CLKCR_PWRSAV: u32 = 1 << 9;
+const CLKCR_BYPASS: u32 = 1 << 10;
+const CLKCR_WIDBUS: u32 = 3 << 11;
+const CMD_WAITRESP_SHIFT: u32 = 6;
+const CMD_CPSMEN: u32 = 1 << 10;
+const DCTRL_DTEN: u32 = 1 << 0;
+const DCTRL_DTDIR: u32 = 1 << 1;
+const DCTRL_DMAEN: u32 = 1 << 3;
+const DCTRL_DBLOCKSIZE_SHIFT: u32 = 4;
+
+const DMA_CR_EN: u32 = 1 << 0;
+const DMA_CR_PFCTRL: u32 = 1 << 5;
+const DMA_CR_DIR_M2P: u32 = 1 << 6;
+const DMA_CR_MINC: u32 = 1 << 10;
+const DMA_CR_PSIZE_32: u32 = 2 << 11;
+const DMA_CR_MSIZE_32: u32 = 2 << 13;
+const DMA_CR_PL_VERY_HIGH: u32 = 3 << 16;
+const DMA_CR_PBURST_INC4: u32 = 1 << 21;
+const DMA_CR_MBURST_INC4: u32 = 1 << 23;
+const DMA_CR_CHSEL_4: u32 = 4 << 25;
+/// Use the FIFO with a full threshold instead of direct mode, as required for bursts.
+const DMA_FCR_FIFO_FULL: u32 = 1 << 2 | 3;
+/// The power on value of the FIFO control register.
+const DMA_FCR_RESET: u32 = 0x21;
+
+#[repr(C)]
+struct SdioRegisters {
+    power: Reg,
+    clkcr: Reg,
+    arg: Reg,
+    cmd: Reg,
+    respcmd: Reg,
+    resp: [Reg; 4],
+    dtimer: Reg,
+    dlen: Reg,
+    dctrl: Reg,
+    dcount: Reg,
+    sta: Reg,
+    icr: Reg,
+    mask: Reg,
+}
+
+#[repr(C)]
+struct DmaStreamRegisters {
+    cr: Reg,
+    ndtr: Reg,
+    par: Reg,
+    m0ar: Reg,
+    m1ar: Reg,
+    fcr: Reg,
+}
+
+#[repr(C)]
+struct DmaRegisters {
+    lisr: Reg,
+    hisr: Reg,
+    lifcr: Reg,
+    hifcr: Reg,
+    streams: [DmaStreamRegisters; 8],
+}
+
+/// The DMA2 streams that can serve the SDIO peripheral, both on channel 4.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum DmaStream {
+    Stream3,
+    Stream6,
+}
+
+impl DmaStream {
+    fn index(self) -> usize {
+        match self {
+            DmaStream::Stream3 => 3,
+            DmaStream::Stream6 => 6,
+        }
+    }
+
+    /// The flag clear register of the stream and the mask of its flags in that register.
+    fn flag_clear_register(self, dma: &DmaRegisters) -> (&Reg, u32) {
+        match self {
+            DmaStream::Stream3 => (&dma.lifcr, 0x3d << 22),
+            DmaStream::Stream6 => (&dma.hifcr, 0x3d << 16),
+        }
+    }
+}
+
+/// The SDIO peripheral and a DMA2 stream of the STM32F2 or STM32F4.
+pub struct Sdio<S, D> {
+    sdio: S,
+    dma: D,
+    sdio_base: usize,
+    dma_base: usize,
+    stream: DmaStream,
+}
+
+impl<S: Deref, D: Deref> Sdio<S, D> {
+    /// Take control of the SDIO peripheral and a stream of DMA2, given as the `SDIO` and `DMA2`
+    /// peripherals of a peripheral access crate such as `stm32f4`. DMA2 is taken as a whole, as
+    /// the peripheral access crates do not split it into streams. Fails and returns the
+    /// peripherals if they are not SDIO and DMA2.
+    ///
+    /// The pins PC12 (CK), PD2 (CMD) and PC8 to PC11 (D0 to D3) should be configured in
+    /// alternate function 12, and the clocks of the SDIO and DMA2 peripherals enabled.
+    pub fn new(sdio: S, dma: D, stream: DmaStream) -> Result<Self, (S, D)> {
+        let sdio_base = address(&sdio);
+        let dma_base = address(&dma);
+        if sdio_base != SDIO_BASE || dma_base != DMA2_BASE {
+            return Err((sdio, dma));
+        }
+
+        Ok(Sdio {
+            sdio,
+            dma,
+            sdio_base,
+            dma_base,
+            stream,
+        })
+    }
+
+    /// Get back the SDIO and DMA2 peripherals.
+    pub fn free(self) -> (S, D) {
+        (self.sdio, self.dma)
+    }
+}
+
+impl<S, D> Sdio<S, D> {
+    fn sdio(&self) -> &SdioRegisters {
+        unsafe { &*(self.sdio_base as *const SdioRegisters) }
+    }
+
+    fn dma(&self) -> &DmaRegisters {
+        unsafe { &*(self.dma_base as *const DmaRegisters) }
+    }
+
+    fn dma_stream(&self) -> &DmaStreamRegisters {
+        &self.dma().streams[self.stream.index()]
+    }
+}
+
+impl<S, D> HostController for Sdio<S, D> {
+    /// The DMA counts 32 bit words in a 16 bit register, and stops at zero even when the SDIO
+    /// controls the flow, so this is the largest number of whole blocks it can transfer at once.
+    const MAX_BLOCKS: usize = 0xffff / (BLOCK_SIZE / 4);
+
+    fn configure(&mut self, clock_divider: u16, bus_width: BusWidth, data_timeout: u32) {
+        let sdio = self.sdio();
+        // NEGEDGE and hardware flow control are left off, as both are broken on these chips.
+        let mut clkcr = CLKCR_PWRSAV;
+        if clock_divider < 2 {
+            clkcr |= CLKCR_BYPASS;
+        } else {
+            clkcr |= (clock_divider.min(Self::MAX_CLOCK_DIVIDER) - 2) as u32 & CLKCR_CLKDIV;
+        }
+
+        clkcr |= match bus_width {
+            BusWidth::Bits1 => 0,
+            BusWidth::Bits4 => 1,
+            BusWidth::Bits8 => 2,
+        } << CLKCR_WIDBUS.trailing_zeros();
+
+        // Enable power, then clock.
+        sdio.clkcr.write(clkcr);
+        sdio.power.write(3);
+        sdio.clkcr.modify(|bits| bits | CLKCR_CLKEN);
+
+        // Set the data timeout.
+        sdio.dtimer.write(data_timeout);
+    }
+
+    fn send_command(&mut self, index: u8, arg: u32, response: Response, _data: DataDirection) {
+        let waitresp = match response {
+            Response::None => 0,
+            _ if response.is_long() => 3,
+            _ => 1,
+        };
+        let sdio = self.sdio();
+        sdio.arg.write(arg);
+        sdio.cmd
+            .write(index as u32 | waitresp << CMD_WAITRESP_SHIFT | CMD_CPSMEN);
+    }
+
+    fn response_index(&self) -> u8 {
+        (self.sdio().respcmd.read() & 0x3f) as u8
+    }
+
+    fn response(&self) -> [u32; 4] {
+        let resp = &self.sdio().resp;
+        [
+            resp[0].read(),
+            resp[1].read(),
+            resp[2].read(),
+            resp[3].read(),
+        ]
+    }
+
+    fn status(&self) -> HostStatus {
+        HostStatus(self.sdio().sta.read())
+    }
+
+    fn clear_status(&mut self) {
+        self.sdio().icr.write(STATUS_ERROR_MASK);
+    }
+
+    fn start_data(&mut self, len: usize, block_size: usize, direction: DataDirection) {
+        let sdio = self.sdio();
+        let mut dctrl =
+            DCTRL_DTEN | DCTRL_DMAEN | (block_size.trailing_zeros() << DCTRL_DBLOCKSIZE_SHIFT);
+        if direction == DataDirection::Read {
+            dctrl |= DCTRL_DTDIR;
+        }
+
+        sdio.dlen.write(len as u32);
+        sdio.dctrl.write(dctrl);
+    }
+
+    unsafe fn start_dma(&mut self, buffer: *mut u8, len: usize, direction: DataDirection) {
+        self.stop_dma();
+        let (ifcr, flags) = self.stream.flag_clear_register(self.dma());
+        let stream = self.dma_stream();
+        // - Clear any pending interrupts.
+        ifcr.write(flags);
+        // - Set the addresses. The SDIO controls the flow, so the number of items is ignored.
+        stream.par.write((self.sdio_base + SDIO_FIFO_OFFSET) as u32);
+        stream.m0ar.write(buffer as u32);
+        stream.ndtr.write((len >> 2) as u32);
+        stream.fcr.write(DMA_FCR_FIFO_FULL);
+        // - Set the channel, word size, bursts, direction and increments.
+        let mut cr = DMA_CR_CHSEL_4
+            | DMA_CR_MBURST_INC4
+            | DMA_CR_PBURST_INC4
+            | DMA_CR_PL_VERY_HIGH
+            | DMA_CR_MSIZE_32
+            | DMA_CR_PSIZE_32
+            | DMA_CR_MINC
+            | DMA_CR_PFCTRL;
+        if direction == DataDirection::Write {
+            cr |= DMA_CR_DIR_M2P;
+        }
+        stream.cr.write(cr);
+        // - Enable the stream.
+        stream.cr.modify(|bits| bits | DMA_CR_EN);
+    }
+
+    fn stop_dma(&mut self) {
+        let stream = self.dma_stream();
+        stream.cr.modify(|bits| bits & !DMA_CR_EN);
+        // The stream only stops once the current transfer has finished.
+        while stream.cr.read() & DMA_CR_EN != 0 {}
+    }
+
+    fn reset(&mut self) {
+        // The RCC can only reset DMA2 as a whole, which other peripherals may be using, so the
+        // stream and the SDIO are put back in their power on state through their own registers.
+        self.stop_dma();
+        let (ifcr, flags) = self.stream.flag_clear_register(self.dma());
+        ifcr.write(flags);
+        let stream = self.dma_stream();
+        stream.cr.write(0);
+        stream.ndtr.write(0);
+        stream.par.write(0);
+        stream.m0ar.write(0);
+        stream.m1ar.write(0);
+        stream.fcr.write(DMA_FCR_RESET);
+
+        let sdio = self.sdio();
+        sdio.power.write(0);
+        sdio.clkcr.write(0);
+        sdio.arg.write(0);
+        sdio.cmd.write(0);
+        sdio.dtimer.write(0);
+        sdio.dlen.write(0);
+        sdio.dctrl.write(0);
+        sdio.mask.write(0);
+        sdio.icr.write(STATUS_ERROR_MASK);
+    }
+}
+
+impl<S, D> Device<Sdio<S, D>> {
+    pub fn new(sdio: Sdio<S, D>, config: Config) -> Self {
+        Device::with_host(sdio, config)
+    }
+
+    /// Recycle the object to get back the SDIO peripheral and DMA stream. Panics if an operation
+    /// is still ongoing.
+    pub fn free(mut self) -> Sdio<S, D> {
+        self.reset();
+        self.host
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::register::MockRegisters;
+    use std::boxed::Box;
+
+    const STREAM6: usize = 0x10 + 6 * 0x18;
+
+    fn sdio(registers: &MockRegisters, dma: &MockRegisters) -> Sdio<(), ()> {
+        Sdio {
+            sdio: (),
+            dma: (),
+            sdio_base: registers.base(),
+            dma_base: dma.base(),
+            stream: DmaStream::Stream6,
+        }
+    }
+
+    #[test]
+    fn only_sdio_and_dma2() {
+        let result = Sdio::new(Box::new(0u32), Box::new(0u32), DmaStream::Stream3);
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn configure() {
+        let (registers, dma) = (MockRegisters::new(0x100), MockRegisters::new(0x100));
+        let mut sdio = sdio(&registers, &dma);
+        sdio.configure(4, BusWidth::Bits4, 1000);
+        assert_eq!(registers.at(0x00).read(), 3);
+        assert_eq!(
+            registers.at(0x04).read(),
+            CLKCR_PWRSAV | CLKCR_CLKEN | 2 | 1 << 11
+        );
+        assert_eq!(registers.at(0x24).read(), 1000);
+
+        sdio.configure(1, BusWidth::Bits1, 1000);
+        assert_eq!(
+            registers.at(0x04).read(),
+            CLKCR_PWRSAV | CLKCR_CLKEN | CLKCR_BYPASS
+        );
+
+        // Dividers that do not fit CLKDIV get the largest one.
+        sdio.configure(1000, BusWidth::Bits1, 1000);
+        assert_eq!(
+            registers.at(0x04).read(),
+            CLKCR_PWRSAV | CLKCR_CLKEN | CLKCR_CLKDIV
+        );
+    }
+
+    #[test]
+    fn command() {
+        let (registers, dma) = (MockRegisters::new(0x100), MockRegisters::new(0x100));
+        let mut sdio = sdio(&registers, &dma);
+        sdio.send_command(17, 5, Response::R1, DataDirection::Read);
+        assert_eq!(registers.at(0x08).read(), 5);
+        assert_eq!(registers.at(0x0c).read(), 17 | 1 << 6 | CMD_CPSMEN);
+        sdio.send_command(2, 0, Response::R2, DataDirection::None);
+        assert_eq!(registers.at(0x0c).read(), 2 | 3 << 6 | CMD_CPSMEN);
+
+        registers.at(0x10).write(17);
+        registers.at(0x14).write(0x900);
+        registers.at(0x34).write(HostStatus::CMDREND);
+        assert_eq!(sdio.response_index(), 17);
+        assert_eq!(sdio.response()[0], 0x900);
+        assert!(sdio.status().cmdrend());
+        sdio.clear_status();
+        assert_eq!(registers.at(0x38).read(), STATUS_ERROR_MASK);
+    }
+
+    #[test]
+    fn dma() {
+        let (registers, dma) = (MockRegisters::new(0x100), MockRegisters::new(0x100));
+        let mut sdio = sdio(&registers, &dma);
+        let len = Sdio::<(), ()>::MAX_BLOCKS * BLOCK_SIZE;
+        assert!(len / 4 <= 0xffff);
+
+        unsafe { sdio.start_dma(0x2000_0000 as *mut u8, len, DataDirection::Read) };
+        sdio.start_data(len, BLOCK_SIZE, DataDirection::Read);
+        assert_eq!(dma.at(0x0c).read(), 0x3d << 16);
+        assert_eq!(
+            dma.at(STREAM6 + 0x08).read(),
+            (registers.base() + 0x80) as u32
+        );
+        assert_eq!(dma.at(STREAM6 + 0x0c).read(), 0x2000_0000);
+        assert_eq!(dma.at(STREAM6 + 0x14).read(), DMA_FCR_FIFO_FULL);
+        let cr = dma.at(STREAM6).read();
+        assert_eq!(cr & (DMA_CR_EN | DMA_CR_DIR_M2P), DMA_CR_EN);
+        assert_eq!(cr & 7 << 25, DMA_CR_CHSEL_4);
+        assert_eq!(registers.at(0x28).read(), len as u32);
+        assert_eq!(
+            registers.at(0x2c).read(),
+            DCTRL_DTEN | DCTRL_DTDIR | DCTRL_DMAEN | 9 << 4
+        );
+
+        sdio.stop_dma();
+        assert_eq!(dma.at(STREAM6).read() & DMA_CR_EN, 0);
+
+        unsafe { sdio.start_dma(0x2000_0000 as *mut u8, 512, DataDirection::Write) };
+        assert_ne!(dma.at(STREAM6).read() & DMA_CR_DIR_M2P, 0);
+        sdio.reset();
+        assert_eq!(dma.at(STREAM6).read(), 0);
+        assert_eq!(dma.at(STREAM6 + 0x14).read(), DMA_FCR_RESET);
+        assert_eq!(registers.at(0x2c).read(), 0);
+    }
+}
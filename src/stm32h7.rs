@@ -0,0 +1,36 @@
+//! The SDMMC1 and SDMMC2 peripherals of the STM32H7 family.
+
+use core::ops::Deref;
+
+use crate::Sdmmc;
+
+const SDMMC1_BASE: usize = 0x5200_7000;
+const SDMMC2_BASE: usize = 0x4802_2400;
+
+impl<P: Deref> Sdmmc<P> {
+    /// Take control of SDMMC1 or SDMMC2, given as a peripheral of a peripheral access crate such
+    /// as `stm32h7`. Fails and returns the peripheral if it is neither. Its pins should be
+    /// configured in their SDMMC alternate function, and its clock enabled.
+    ///
+    /// The IDMA of SDMMC1 can only reach the AXI SRAM and external memories, so its buffers must
+    /// not be placed in the TCM or the D2 and D3 SRAMs. The IDMA of SDMMC2 can reach all memories
+    /// except the TCM. Buffers that do not start and end on a 32 byte cache line boundary share
+    /// lines with other data, which must not be written while a read is running.
+    pub fn new(sdmmc: P) -> Result<Self, P> {
+        Sdmmc::with_peripheral(sdmmc, &[SDMMC1_BASE, SDMMC2_BASE], true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sdmmc_v2::tests::Peripheral;
+
+    #[test]
+    fn only_sdmmc1_and_sdmmc2() {
+        for base in [SDMMC1_BASE, SDMMC2_BASE] {
+            assert!(Sdmmc::new(Peripheral(base)).is_ok());
+        }
+        assert!(Sdmmc::new(Peripheral(0x5200_8000)).is_err());
+    }
+}
@@ -0,0 +1,33 @@
+//! The SDMMC peripherals of the STM32L4+ family: the L4R, L4S, L4P and L4Q parts.
+
+use core::ops::Deref;
+
+use crate::Sdmmc;
+
+const SDMMC1_BASE: usize = 0x5006_2400;
+const SDMMC2_BASE: usize = 0x5006_2800;
+
+impl<P: Deref> Sdmmc<P> {
+    /// Take control of SDMMC1 or SDMMC2, which only the L4P and L4Q parts have, given as a
+    /// peripheral of a peripheral access crate such as `stm32l4`. Fails and returns the
+    /// peripheral if it is neither. The pins of SDMMC1 are PC12 (CK), PD2 (CMD) and PC8 to PC11
+    /// (D0 to D3) in alternate function 12. The pins should be configured, and the clock of the
+    /// peripheral enabled.
+    pub fn new(sdmmc: P) -> Result<Self, P> {
+        Sdmmc::with_peripheral(sdmmc, &[SDMMC1_BASE, SDMMC2_BASE], false)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sdmmc_v2::tests::Peripheral;
+
+    #[test]
+    fn only_sdmmc1_and_sdmmc2() {
+        for base in [SDMMC1_BASE, SDMMC2_BASE] {
+            assert!(Sdmmc::new(Peripheral(base)).is_ok());
+        }
+        assert!(Sdmmc::new(Peripheral(0x5006_2c00)).is_err());
+    }
+}
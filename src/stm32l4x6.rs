@@ -1,101 +1,155 @@
+use stm32l4xx_hal::rcc::{Clocks, MsiFreq, AHB1, APB2};
 use stm32l4xx_hal::stm32;
+use stm32l4xx_hal::time::Hertz;
 
-use crate::Error::*;
-use crate::{
-    AppCommand, Block, BlockCount, BlockIndex, BusWidth, CardHost, CardVersion, Command, Error,
-    SDStatus, BLOCK_SIZE, CID, CSD, CardStatus,
-};
-use nb::block;
-use nb::Error::{Other, WouldBlock};
+use crate::BLOCK_SIZE;
+use crate::{BusWidth, Config, DataDirection, Device, Error, HostController, HostStatus, Response};
 
 const SDMMC_FIFO_OFFSET: u32 = 0x4001_2800 + 0x80;
-const SEND_IF_COND_PATTERN: u32 = 0x0000_01aa;
 const STATUS_ERROR_MASK: u32 = 0x0000_05ff;
+/// The highest frequency SDMMC1 may be clocked at.
+const MAX_KERNEL_CLOCK: u32 = 48_000_000;
 
-use stm32l4xx_hal::gpio;
-type Pin = gpio::Alternate<gpio::AF12, gpio::Input<gpio::Floating>>;
-type ClockPin = gpio::gpioc::PC12<Pin>;
-type CommandPin = gpio::gpiod::PD2<Pin>;
-type Data0Pin = gpio::gpioc::PC8<Pin>;
-type Data1Pin = gpio::gpioc::PC9<Pin>;
-type Data2Pin = gpio::gpioc::PC10<Pin>;
-type Data3Pin = gpio::gpioc::PC11<Pin>;
-pub type Pins = (ClockPin, CommandPin, Data0Pin, Data1Pin, Data2Pin, Data3Pin);
+// The HAL only hands out the registers behind its AHB1 and APB2 handles inside its own crate, so
+// they are accessed through the RCC pointer. Borrowing the handle still guarantees that nothing
+// else modifies them at the same time.
 
-#[derive(Copy, Clone, Debug)]
-enum State {
-    Uninitialized,
-    Init1(bool),
-    Ready,
-    Reading,
-    Writing,
-    Erasing,
+fn ahb1enr(_ahb1: &mut AHB1) -> &stm32::rcc::AHB1ENR {
+    unsafe { &(*stm32::RCC::ptr()).ahb1enr }
 }
 
-pub struct Device {
-    sdmmc: stm32::SDMMC1,
-    dma: stm32::DMA2,
-    pins: Pins,
-    config: Config,
-    state: State,
-    rca: u32,
-    /// Card Specific Data
-    csd: CSD,
-    cid: CID,
-    card_version: CardVersion,
+fn apb2enr(_apb2: &mut APB2) -> &stm32::rcc::APB2ENR {
+    unsafe { &(*stm32::RCC::ptr()).apb2enr }
 }
 
-pub struct Config {
-    /// The width of the data bus in bits, either one or four.
-    pub bus_width: BusWidth,
-    /// Value to divide the clock speed by. Zero or one means bypass clock divider.
-    pub clock_divider: u8,
-    /// The number of clock cycles to wait for data transfer to complete.
-    pub data_timeout: u32,
+fn apb2rstr(_apb2: &mut APB2) -> &stm32::rcc::APB2RSTR {
+    unsafe { &(*stm32::RCC::ptr()).apb2rstr }
 }
 
-impl Default for Config {
-    fn default() -> Self {
-        Config {
-            bus_width: BusWidth::Bits1,
-            clock_divider: 4,
-            data_timeout: 0x1000000,
-        }
+use stm32l4xx_hal::gpio::{self, Alternate, AF12};
+
+mod sealed {
+    pub trait Configure {
+        /// Turn on the internal pull-ups the lines need and switch the outputs to the highest
+        /// speed.
+        fn configure(&mut self);
     }
 }
 
-impl Device {
-    pub fn new(sdmmc: stm32::SDMMC1, dma: stm32::DMA2, pins: Pins, config: Config) -> Device {
-        Device {
-            sdmmc,
-            dma,
-            pins,
-            config,
-            state: State::Uninitialized,
-            rca: 0,
-            csd: CSD::V1([0; 4]),
-            cid: [0; 4],
-            card_version: CardVersion::V1SC,
-        }
-    }
+use sealed::Configure;
+
+/// A pin that can carry the clock line of SDMMC1.
+pub trait ClockPin: Configure {}
+/// A pin that can carry the command line of SDMMC1.
+pub trait CommandPin: Configure {}
+/// A pin that can carry data line 0 of SDMMC1.
+pub trait Data0Pin: Configure {}
+/// A pin that can carry data line 1 of SDMMC1.
+pub trait Data1Pin: Configure {}
+/// A pin that can carry data line 2 of SDMMC1.
+pub trait Data2Pin: Configure {}
+/// A pin that can carry data line 3 of SDMMC1.
+pub trait Data3Pin: Configure {}
+
+/// Set the speed and pull-up of a pin. This is not synchronized with other users of the port, so
+/// it must not run concurrently with any other GPIO configuration.
+fn configure_pin(gpio: &stm32::gpioc::RegisterBlock, index: u32, pull_up: bool) {
+    let shift = 2 * index;
+    gpio.ospeedr
+        .modify(|r, w| unsafe { w.bits(r.bits() | 0b11 << shift) });
+    gpio.pupdr
+        .modify(|r, w| unsafe { w.bits(r.bits() & !(0b11 << shift) | (pull_up as u32) << shift) });
+}
 
-    fn reset(&mut self) {
-        self.state = State::Uninitialized;
-        let rcc = unsafe { &*stm32::RCC::ptr() };
-        rcc.ahb1rstr.modify(|_, w| w.dma2rst().set_bit());
-        rcc.apb2rstr.modify(|_, w| w.sdmmcrst().set_bit());
-        rcc.ahb1rstr.modify(|_, w| w.dma2rst().clear_bit());
-        rcc.apb2rstr.modify(|_, w| w.sdmmcrst().clear_bit());
+macro_rules! pins {
+    ($($PIN:ident: ($port:ident, $GPIO:ident, $i:expr, $Line:ident, $pull_up:expr),)+) => {
+        $(
+            impl<MODE> Configure for gpio::$port::$PIN<Alternate<AF12, MODE>> {
+                fn configure(&mut self) {
+                    configure_pin(unsafe { &*stm32::$GPIO::ptr() }, $i, $pull_up);
+                }
+            }
+
+            impl<MODE> $Line for gpio::$port::$PIN<Alternate<AF12, MODE>> {}
+        )+
+    };
+}
+
+// The clock is always driven by the host. The other lines are open drain on the card side in
+// identification mode and need pull-ups.
+pins! {
+    PC12: (gpioc, GPIOC, 12, ClockPin, false),
+    PD2: (gpiod, GPIOD, 2, CommandPin, true),
+    PC8: (gpioc, GPIOC, 8, Data0Pin, true),
+    PC9: (gpioc, GPIOC, 9, Data1Pin, true),
+    PC10: (gpioc, GPIOC, 10, Data2Pin, true),
+    PC11: (gpioc, GPIOC, 11, Data3Pin, true),
+}
+
+/// The pins used by SDMMC1, in alternate function 12: a tuple of the clock, command and data
+/// line 0 pins for a 1 bit bus, optionally followed by the data line 1 to 3 pins for a 4 bit
+/// bus.
+pub trait Pins: Configure {
+    /// The widest data bus the pins can carry.
+    const BUS_WIDTH: BusWidth;
+}
+
+impl<CLK: ClockPin, CMD: CommandPin, D0: Data0Pin> Configure for (CLK, CMD, D0) {
+    fn configure(&mut self) {
+        self.0.configure();
+        self.1.configure();
+        self.2.configure();
     }
+}
 
-    /// Recycle the object to get back the SDMMC and DMA peripherals. Panics if an operation is
-    /// still ongoing.
-    pub fn free(mut self) -> (stm32::SDMMC1, stm32::DMA2, Pins) {
-        self.reset();
-        (self.sdmmc, self.dma, self.pins)
+impl<CLK: ClockPin, CMD: CommandPin, D0: Data0Pin> Pins for (CLK, CMD, D0) {
+    const BUS_WIDTH: BusWidth = BusWidth::Bits1;
+}
+
+impl<CLK, CMD, D0, D1, D2, D3> Configure for (CLK, CMD, D0, D1, D2, D3)
+where
+    CLK: ClockPin,
+    CMD: CommandPin,
+    D0: Data0Pin,
+    D1: Data1Pin,
+    D2: Data2Pin,
+    D3: Data3Pin,
+{
+    fn configure(&mut self) {
+        self.0.configure();
+        self.1.configure();
+        self.2.configure();
+        self.3.configure();
+        self.4.configure();
+        self.5.configure();
     }
+}
+
+impl<CLK, CMD, D0, D1, D2, D3> Pins for (CLK, CMD, D0, D1, D2, D3)
+where
+    CLK: ClockPin,
+    CMD: CommandPin,
+    D0: Data0Pin,
+    D1: Data1Pin,
+    D2: Data2Pin,
+    D3: Data3Pin,
+{
+    const BUS_WIDTH: BusWidth = BusWidth::Bits4;
+}
+
+/// The SDMMC1 peripheral and channel 4 of DMA2 of the STM32L4x6, along with the pins they use.
+pub struct Registers<P> {
+    sdmmc: stm32::SDMMC1,
+    dma: stm32::DMA2,
+    pins: P,
+}
 
-    fn init_peri(&mut self, clock_divider: u8) {
+impl<P> HostController for Registers<P> {
+    /// The DMA counts 32 bit words in a 16 bit register, so this is the largest number of whole
+    /// blocks it can transfer at once.
+    const MAX_BLOCKS: usize = 0xffff / (BLOCK_SIZE / 4);
+
+    fn configure(&mut self, clock_divider: u16, bus_width: BusWidth, data_timeout: u32) {
         // Enable power, then clock.
         self.sdmmc
             .clkcr
@@ -104,15 +158,19 @@ impl Device {
         if clock_divider < 2 {
             self.sdmmc.clkcr.modify(|_, w| w.bypass().set_bit());
         } else {
-            self.sdmmc
-                .clkcr
-                .modify(|_, w| unsafe { w.clkdiv().bits(clock_divider - 2) });
+            self.sdmmc.clkcr.modify(|_, w| unsafe {
+                w.bypass()
+                    .clear_bit()
+                    .clkdiv()
+                    .bits((clock_divider.min(Self::MAX_CLOCK_DIVIDER) - 2) as u8)
+            });
         }
 
         self.sdmmc.clkcr.modify(|_, w| unsafe {
-            w.widbus().bits(match self.config.bus_width {
+            w.widbus().bits(match bus_width {
                 BusWidth::Bits1 => 0,
                 BusWidth::Bits4 => 1,
+                BusWidth::Bits8 => 2,
             })
         });
 
@@ -122,187 +180,80 @@ impl Device {
         self.sdmmc.clkcr.modify(|_, w| w.clken().set_bit());
 
         // Set the data timeout.
-        self.sdmmc
-            .dtimer
-            .write(|w| unsafe { w.bits(self.config.data_timeout) });
+        self.sdmmc.dtimer.write(|w| unsafe { w.bits(data_timeout) });
 
         // Select sdmmc for dma 2 channel 4.
         self.dma.cselr.modify(|_, w| w.c4s().bits(0x7));
     }
 
-    pub fn host_status(&self) -> u32 {
-        self.sdmmc.sta.read().bits()
-    }
-
-    fn card_status(&mut self) -> Result<CardStatus, Error> {
-        self.init_peri(self.config.clock_divider);
-        Ok(CardStatus(self.card_command_short(Command::SEND_STATUS, self.rca)?))
-    }
-
-    fn check_operating_conditions(&mut self) -> Result<(), Error> {
-        match self.card_command_short(Command::SEND_IF_COND, SEND_IF_COND_PATTERN) {
-            Err(e) => Err(e),
-            Ok(received_pattern) => {
-                if received_pattern != SEND_IF_COND_PATTERN {
-                    Err(OperatingConditionsNotSupported)
-                } else {
-                    Ok(())
-                }
-            }
-        }
-    }
-
-    fn app_command_short(&mut self, cmd: AppCommand, arg: u32) -> Result<u32, Error> {
-        self.card_command_short(Command::APP_COMMAND, self.rca)?;
-        self.sdmmc.arg.write(|w| unsafe { w.bits(arg) });
-        self.sdmmc.cmd.write(|w| unsafe {
-            w.cmdindex()
-                .bits(cmd as u8)
-                .waitresp()
-                .bits(1)
-                .cpsmen()
-                .set_bit()
-        });
-
-        block!(self.check_command(true))?;
-        Ok(self.sdmmc.resp1.read().bits())
-    }
-
-    fn acmd41(&mut self, hcs: bool) -> Result<u32, Error> {
-        self.card_command_short(Command::APP_COMMAND, 0)?;
-        let arg = 0x0010_0000 | (hcs as u32) << 30;
+    fn send_command(&mut self, index: u8, arg: u32, response: Response, _data: DataDirection) {
         self.sdmmc.arg.write(|w| unsafe { w.bits(arg) });
         self.sdmmc.cmd.write(|w| unsafe {
             w.cmdindex()
-                .bits(AppCommand::SD_SEND_OP_COND as u8)
+                .bits(index)
                 .waitresp()
-                .bits(1)
+                .bits(match response {
+                    Response::None => 0,
+                    _ if response.is_long() => 3,
+                    _ => 1,
+                })
                 .cpsmen()
                 .set_bit()
         });
-
-        // acmd41 does not set crc so we expect crcfail
-        match block!(self.check_command(true)) {
-            Err(CRCFail) => Ok(()),
-            x => x,
-        }?;
-
-        Ok(self.sdmmc.resp1.read().bits())
     }
 
-    fn card_command_none(&mut self, cmd: Command, arg: u32) -> Result<(), Error> {
-        self.sdmmc.arg.write(|w| unsafe { w.bits(arg) });
-        self.sdmmc.cmd.write(|w| unsafe {
-            w.cmdindex()
-                .bits(cmd as u8)
-                .waitresp()
-                .bits(0)
-                .cpsmen()
-                .set_bit()
-        });
-
-        block!(self.check_command(false))
-    }
-
-    fn card_command_short(&mut self, cmd: Command, arg: u32) -> Result<u32, Error> {
-        self.sdmmc.arg.write(|w| unsafe { w.bits(arg) });
-        self.sdmmc.cmd.write(|w| unsafe {
-            w.cmdindex()
-                .bits(cmd as u8)
-                .waitresp()
-                .bits(1)
-                .cpsmen()
-                .set_bit()
-        });
-
-        block!(self.check_command(true))?;
-        if self.sdmmc.respcmd.read().respcmd().bits() != cmd as u8 {
-            return Err(UnexpectedResponse);
-        }
-
-        Ok(self.sdmmc.resp1.read().bits())
+    fn response_index(&self) -> u8 {
+        self.sdmmc.respcmd.read().respcmd().bits()
     }
 
-    fn card_command_long(&mut self, cmd: Command, arg: u32) -> Result<[u32; 4], Error> {
-        self.sdmmc.arg.write(|w| unsafe { w.bits(arg) });
-        self.sdmmc.cmd.write(|w| unsafe {
-            w.cmdindex()
-                .bits(cmd as u8)
-                .waitresp()
-                .bits(3)
-                .cpsmen()
-                .set_bit()
-        });
-
-        block!(self.check_command(true))?;
-        // This delay helps with command recognition in the logic analyzer.
-        // TODO: Remove
-        let foo = 0u32;
-        for _ in 0..0x1000 {
-            unsafe {
-                core::ptr::read_volatile(&foo);
-            }
-        }
-
-        Ok([
+    fn response(&self) -> [u32; 4] {
+        [
             self.sdmmc.resp1.read().bits(),
             self.sdmmc.resp2.read().bits(),
             self.sdmmc.resp3.read().bits(),
             self.sdmmc.resp4.read().bits(),
-        ])
+        ]
     }
 
-    fn check_ready(&mut self) -> Result<(), Error> {
-        use State::*;
-        match self.state {
-            Uninitialized | Init1(_) => Err(Error::Uninitialized),
-            Ready => {
-                self.init_peri(self.config.clock_divider);
-                Ok(())
-            }
-            Reading | Writing | Erasing => Err(Error::Busy),
-        }
+    fn status(&self) -> HostStatus {
+        HostStatus(self.sdmmc.sta.read().bits())
     }
 
-    fn check_command(&mut self, expect_response: bool) -> nb::Result<(), Error> {
-        let status = self.sdmmc.sta.read();
-        if status.cmdact().bit() {
-            return Err(WouldBlock);
-        }
+    fn clear_status(&mut self) {
         self.sdmmc
             .icr
             .write(|w| unsafe { w.bits(STATUS_ERROR_MASK) });
-        if status.ccrcfail().bit() {
-            Err(Other(CRCFail))
-        } else if status.ctimeout().bit() {
-            Err(Other(Timeout))
-        } else if expect_response && !status.cmdrend().bit() {
-            Err(Other(UnknownResult))
-        } else if !expect_response && !status.cmdsent().bit() {
-            Err(Other(UnknownResult))
-        } else {
-            Ok(())
-        }
     }
 
-    unsafe fn setup_read(&mut self, dest: &mut [u8]) {
-        let size = dest.len();
-        assert!(size.is_power_of_two() && size & 3 == 0 && size < 0x40000);
+    fn start_data(&mut self, len: usize, block_size: usize, direction: DataDirection) {
         // a. Set the data length register.
-        self.sdmmc.dlen.write(|w| w.bits(size as u32));
-        // b. Set the dma channel.
-        //    - Clear any pending interrupts.
+        self.sdmmc.dlen.write(|w| unsafe { w.bits(len as u32) });
+        // b. Set the data control register.
+        self.sdmmc.dctrl.write(|w| unsafe {
+            w.dten()
+                .set_bit()
+                .dtdir()
+                .bit(direction == DataDirection::Read)
+                .dmaen()
+                .set_bit()
+                .dblocksize()
+                .bits(block_size.trailing_zeros() as u8)
+        });
+    }
+
+    unsafe fn start_dma(&mut self, buffer: *mut u8, len: usize, direction: DataDirection) {
+        // - Clear any pending interrupts.
         self.dma.ifcr.write(|w| w.cgif4().set_bit());
-        //    - Set the channel source address.
-        self.dma.cmar4.write(|w| w.bits(dest.as_ptr() as u32));
-        //    - Set the channel destination address.
+        // - Set the channel memory address.
+        self.dma.cmar4.write(|w| w.bits(buffer as u32));
+        // - Set the channel peripheral address.
         self.dma.cpar4.write(|w| w.bits(SDMMC_FIFO_OFFSET));
-        //    - Set the number of words to transfer.
-        self.dma.cndtr4.write(|w| w.ndt().bits((size >> 2) as u16));
-        //    - Set the word size, direction and increments.
+        // - Set the number of words to transfer.
+        self.dma.cndtr4.write(|w| w.ndt().bits((len >> 2) as u16));
+        // - Set the word size, direction and increments.
         self.dma.ccr4.write(|w| {
             w.dir()
-                .clear_bit()
+                .bit(direction == DataDirection::Write)
                 .minc()
                 .set_bit()
                 .pinc()
@@ -312,258 +263,204 @@ impl Device {
                 .psize()
                 .bits32()
         });
-        //    - Enable the channel.
+        // - Enable the channel.
         self.dma.ccr4.modify(|_, w| w.en().set_bit());
-        // c. Set the data control register:
-        self.sdmmc.dctrl.write(|w| {
-            w.dten()
-                .set_bit()
-                .dtdir()
-                .set_bit()
-                .dmaen()
-                .set_bit()
-                .dblocksize()
-                .bits(size.trailing_zeros() as u8)
-        });
     }
-}
 
-impl CardHost for Device {
-    fn init_card(&mut self) -> nb::Result<(), Error> {
-        use State::*;
-        match self.state {
-            Reading | Writing | Erasing => {
-                self.reset();
-                Err(WouldBlock)
-            }
+    fn stop_dma(&mut self) {
+        self.dma.ccr4.modify(|_, w| w.en().clear_bit());
+    }
 
-            Uninitialized | Ready => {
-                self.init_peri(0x80);
-                // * -> idle
-                self.card_command_none(Command::GO_IDLE_STATE, 0)?;
-                // Determine card version.
-                let v2 = match self.check_operating_conditions() {
-                    Err(Timeout) => false,
-                    Ok(_) => true,
-                    Err(e) => return Err(Other(e)),
-                };
-
-                self.state = Init1(v2);
-                // Recurse once to start the next part.
-                self.init_card()
-            }
+    fn reset(&mut self) {
+        // Only channel 4 of DMA2 belongs to SDMMC1, so reset that instead of all of DMA2.
+        self.dma.ccr4.reset();
+        self.dma.cndtr4.reset();
+        self.dma.ifcr.write(|w| w.cgif4().set_bit());
 
-            Init1(v2) => {
-                self.init_peri(0x80);
-                // idle -> ready
-                let result = match self.acmd41(v2) {
-                    Err(Timeout) if !v2 => Err(Other(NoCard)),
-                    Ok(result) if result >> 31 == 0 => Err(WouldBlock),
-                    Ok(x) => Ok(x),
-                    Err(e) => {
-                        self.state = Uninitialized;
-                        Err(Other(e))
-                    }
-                }?;
-
-                self.state = Uninitialized;
-                let ccs = (result >> 30) & 1 != 0;
-                self.card_version = match (v2, ccs) {
-                    (false, _) => CardVersion::V1SC,
-                    (true, false) => CardVersion::V2SC,
-                    (true, true) => CardVersion::V2HC,
-                };
-
-                // ready -> ident
-                self.cid = self.card_command_long(Command::ALL_SEND_CID, 0)?;
-
-                // ident -> stby
-                let card_rca_status = self.card_command_short(Command::SEND_RELATIVE_ADDR, 0)?;
-                self.rca = card_rca_status & 0xffff_0000;
-                let csd = self.card_command_long(Command::SEND_CSD, self.rca)?;
-                self.csd = match self.card_version {
-                    CardVersion::V1SC | CardVersion::V2SC => CSD::V1(csd),
-                    CardVersion::V2HC => CSD::V2(csd),
-                };
-
-                // stby -> tran
-                self.card_command_short(Command::SELECT_CARD, self.rca)?;
-                self.app_command_short(
-                    AppCommand::SET_BUS_WIDTH,
-                    match self.config.bus_width {
-                        BusWidth::Bits1 => 0,
-                        BusWidth::Bits4 => 2,
-                    },
-                )?;
-
-                self.state = Ready;
-                Ok(())
-            }
-        }
+        // The reset bit of SDMMC1 is behind the APB2 handle of the HAL, so restore the power on
+        // values of the registers instead.
+        self.sdmmc.power.reset();
+        self.sdmmc.clkcr.reset();
+        self.sdmmc.arg.reset();
+        self.sdmmc.cmd.reset();
+        self.sdmmc.dtimer.reset();
+        self.sdmmc.dlen.reset();
+        self.sdmmc.dctrl.reset();
+        self.sdmmc.mask.reset();
+        self.sdmmc
+            .icr
+            .write(|w| unsafe { w.bits(STATUS_ERROR_MASK) });
     }
+}
 
-    fn erase_card(&mut self) -> Result<(), Error> {
-        self.check_ready()?;
-        self.card_command_short(Command::ERASE_WR_BLK_START, 0)?;
-        let card_size = self.card_size()?;
-        self.card_command_short(Command::ERASE_WR_BLK_END, card_size - 1)?;
-        // 2 means Full User area Logical Erase
-        self.card_command_short(Command::ERASE, 2)?;
-        self.state = State::Erasing;
-        Ok(())
-    }
+/// The frequency of the 48 MHz clock (CLK48) that clocks SDMMC1.
+pub trait KernelClock {
+    fn frequency(&self) -> Result<u32, Error>;
+}
 
-    fn card_id(&mut self) -> Result<CID, Error> {
-        match self.state {
-            State::Uninitialized => Err(Error::Uninitialized),
-            State::Init1(_) => Err(Error::Uninitialized),
-            _ => Ok(self.cid),
+/// A frequency known to the application, for example from the PLLSAI1 Q output.
+impl KernelClock for Hertz {
+    fn frequency(&self) -> Result<u32, Error> {
+        match self.0 {
+            0 => Err(Error::ClockConfiguration),
+            frequency => Ok(frequency),
         }
     }
+}
 
-    fn card_size(&mut self) -> Result<BlockCount, Error> {
-        match self.state {
-            State::Uninitialized => Err(Error::Uninitialized),
-            State::Init1(_) => Err(Error::Uninitialized),
-            _ => Ok(self.csd.capacity()),
+/// The frequency from the clock configuration of the HAL, which only knows about the MSI and
+/// HSI48 sources of CLK48.
+impl KernelClock for Clocks {
+    fn frequency(&self) -> Result<u32, Error> {
+        let rcc = unsafe { &*stm32::RCC::ptr() };
+        match rcc.ccipr.read().clk48sel().bits() {
+            0b00 if self.hsi48() => Ok(48_000_000),
+            0b11 => self
+                .msi()
+                .map(msi_frequency)
+                .ok_or(Error::ClockConfiguration),
+            _ => Err(Error::ClockConfiguration),
         }
     }
+}
 
-    fn read_sd_status(&mut self) -> Result<SDStatus, Error> {
-        self.check_ready()?;
-        let mut result = SDStatus([0; 64]);
-        unsafe {
-            self.setup_read(&mut result.0);
-        }
-
-        self.app_command_short(AppCommand::SD_STATUS, self.rca)?;
-        self.state = State::Reading;
-        block!(self.result())?;
-        Ok(result)
-    }
+/// The sources of CLK48. CLK48 also clocks USB and the RNG.
+#[derive(Copy, Clone, Debug)]
+pub enum Clock48 {
+    /// The internal 48 MHz oscillator, only available on the STM32L49x and STM32L4Ax.
+    Hsi48,
+    /// The Q output of PLLSAI1, running at the given frequency.
+    PllSai1Q(Hertz),
+    /// The Q output of the main PLL, running at the given frequency.
+    PllQ(Hertz),
+    /// The MSI oscillator, at the range in the clock configuration of the HAL.
+    Msi,
+}
 
-    fn erase(&mut self, start: BlockIndex, end: BlockIndex) -> Result<(), Error> {
-        self.check_ready()?;
-        self.card_command_short(Command::ERASE_WR_BLK_START, start)?;
-        self.card_command_short(Command::ERASE_WR_BLK_END, end)?;
-        self.card_command_short(Command::ERASE, 0)?;
-        self.state = State::Erasing;
-        Ok(())
+impl Clock48 {
+    /// Check that the source is running and return its frequency.
+    fn frequency(self, clocks: &Clocks) -> Result<u32, Error> {
+        let rcc = unsafe { &*stm32::RCC::ptr() };
+        let cr = rcc.cr.read();
+        let (running, frequency) = match self {
+            Clock48::Hsi48 => (rcc.crrcr.read().hsi48rdy().bit_is_set(), 48_000_000),
+            Clock48::PllSai1Q(frequency) => (
+                cr.pllsai1rdy().bit_is_set() && rcc.pllsai1cfgr.read().pllsai1qen().bit_is_set(),
+                frequency.0,
+            ),
+            Clock48::PllQ(frequency) => (
+                cr.pllrdy().bit_is_set() && rcc.pllcfgr.read().pllqen().bit_is_set(),
+                frequency.0,
+            ),
+            Clock48::Msi => (
+                cr.msirdy().bit_is_set(),
+                clocks.msi().map_or(0, msi_frequency),
+            ),
+        };
+
+        match frequency {
+            1..=MAX_KERNEL_CLOCK if running => Ok(frequency),
+            _ => Err(Error::ClockConfiguration),
+        }
     }
 
-    unsafe fn read_block(&mut self, block: &mut Block, address: BlockIndex) -> Result<(), Error> {
-        self.check_ready()?;
-        self.setup_read(block);
-        match self.card_command_short(Command::READ_BLOCK, address) {
-            Ok(_) => {
-                self.state = State::Reading;
-                Ok(())
-            }
-
-            Err(e) => {
-                // TODO: Disable DMA.
-                Err(e)
-            }
+    /// The value of the CLK48SEL field of CCIPR that selects the source.
+    fn clk48sel(self) -> u8 {
+        match self {
+            Clock48::Hsi48 => 0b00,
+            Clock48::PllSai1Q(_) => 0b01,
+            Clock48::PllQ(_) => 0b10,
+            Clock48::Msi => 0b11,
         }
     }
+}
 
-    #[allow(unused_unsafe)]
-    unsafe fn write_blocks(&mut self, blocks: &[Block], address: BlockIndex) -> Result<(), Error> {
-        self.check_ready()?;
-
-        self.card_command_short(Command::SET_BLOCK_COUNT, blocks.len() as u32)?;
-
-        // a. Set the data length register.
-        self.sdmmc
-            .dlen
-            .write(|w| unsafe { w.bits((blocks.len() * BLOCK_SIZE) as u32) });
-
-        // b. Set the dma channel.
-        //    - Set the channel source address.
-        self.dma
-            .cmar4
-            .write(|w| unsafe { w.bits(blocks as *const [Block] as *const Block as u32) });
-        //    - Set the channel destination address.
-        self.dma
-            .cpar4
-            .write(|w| unsafe { w.bits(SDMMC_FIFO_OFFSET) });
-        //    - Set the number of words to transfer.
-        self.dma
-            .cndtr4
-            .write(|w| w.ndt().bits(blocks.len() as u16 * 0x80));
-
-        //    - Set the word size, direction and increments.
-        self.dma.ccr4.write(|w| {
-            w.dir()
-                .set_bit()
-                .minc()
-                .set_bit()
-                .pinc()
-                .clear_bit()
-                .msize()
-                .bits32()
-                .psize()
-                .bits32()
-        });
-
-        //    - Enable the channel.
-        self.dma.ccr4.modify(|_, w| w.en().set_bit());
-
-        // c. Set the address.
-        // d. Set the command register.
-        self.card_command_short(Command::WRITE_MULTIPLE_BLOCK, address)?;
-        self.state = State::Writing;
+fn msi_frequency(range: MsiFreq) -> u32 {
+    match range {
+        MsiFreq::RANGE100K => 100_000,
+        MsiFreq::RANGE200K => 200_000,
+        MsiFreq::RANGE400K => 400_000,
+        MsiFreq::RANGE800K => 800_000,
+        MsiFreq::RANGE1M => 1_000_000,
+        MsiFreq::RANGE2M => 2_000_000,
+        MsiFreq::RANGE4M => 4_000_000,
+        MsiFreq::RANGE8M => 8_000_000,
+        MsiFreq::RANGE16M => 16_000_000,
+        MsiFreq::RANGE24M => 24_000_000,
+        MsiFreq::RANGE32M => 32_000_000,
+        MsiFreq::RANGE48M => 48_000_000,
+    }
+}
 
-        // e. Set the data control register:
-        self.sdmmc.dctrl.write(|w| unsafe {
-            w.dten()
-                .set_bit()
-                .dtdir()
-                .clear_bit()
-                .dmaen()
-                .set_bit()
-                .dblocksize()
-                .bits(0x9)
-        });
+impl<P: Pins> Device<Registers<P>> {
+    /// Configure the pins and create a device. The bus width in `config` is limited to the
+    /// number of data lines in `pins`. The clock dividers are picked from the frequency of
+    /// `clock`, which may be the `Clocks` of the HAL or a `Hertz` value.
+    ///
+    /// The clocks of SDMMC1 and DMA2 must be enabled and CLK48 selected, otherwise this fails
+    /// with `ClockConfiguration`. Use `with_rcc` to have this done.
+    pub fn new(
+        sdmmc: stm32::SDMMC1,
+        dma: stm32::DMA2,
+        mut pins: P,
+        clock: impl KernelClock,
+        mut config: Config,
+    ) -> Result<Self, Error> {
+        let rcc = unsafe { &*stm32::RCC::ptr() };
+        if rcc.ahb1enr.read().dma2en().bit_is_clear() || rcc.apb2enr.read().sdmmcen().bit_is_clear()
+        {
+            return Err(Error::ClockConfiguration);
+        }
 
-        Ok(())
+        let frequency = clock.frequency()?;
+        pins.configure();
+        config.bus_width = match (P::BUS_WIDTH, config.bus_width) {
+            (BusWidth::Bits1, _) => BusWidth::Bits1,
+            (BusWidth::Bits4, BusWidth::Bits8) => BusWidth::Bits4,
+            (_, bus_width) => bus_width,
+        };
+        Ok(Device::with_host(Registers { sdmmc, dma, pins }, config).with_kernel_clock(frequency))
     }
 
-    fn result(&mut self) -> nb::Result<(), Error> {
-        let status = self.sdmmc.sta.read();
-        match self.state {
-            State::Uninitialized | State::Init1(_) => Err(Other(Error::Uninitialized)),
-            State::Ready => Err(Other(NoOperation)),
-            State::Reading if status.rxact().bit() => Err(WouldBlock),
-            State::Writing if status.txact().bit() => Err(WouldBlock),
-            State::Reading | State::Writing => Ok(()),
-            State::Erasing => {
-                return if self.card_status()?.ready_for_data() {
-                    self.state = State::Ready;
-                    Ok(())
-                } else {
-                    Err(WouldBlock)
-                }
-            }
-        }?;
+    /// Enable and reset SDMMC1, enable DMA2, select `source` as CLK48, and create a device like
+    /// `new`. Fails with `ClockConfiguration` if the source is not running or runs faster than
+    /// 48 MHz. The data clocks in `config` are limited to what APB2 can keep up with. DMA2 is
+    /// shared with other peripherals, so it is not reset.
+    #[allow(clippy::too_many_arguments)]
+    pub fn with_rcc(
+        sdmmc: stm32::SDMMC1,
+        dma: stm32::DMA2,
+        pins: P,
+        source: Clock48,
+        clocks: &Clocks,
+        ahb1: &mut AHB1,
+        apb2: &mut APB2,
+        mut config: Config,
+    ) -> Result<Self, Error> {
+        let frequency = source.frequency(clocks)?;
+
+        // The HAL has no handle for CCIPR and never writes it, so only CLK48SEL is changed.
+        let rcc = unsafe { &*stm32::RCC::ptr() };
+        rcc.ccipr
+            .modify(|_, w| unsafe { w.clk48sel().bits(source.clk48sel()) });
+        ahb1enr(ahb1).modify(|_, w| w.dma2en().set_bit());
+        apb2enr(apb2).modify(|_, w| w.sdmmcen().set_bit());
+        apb2rstr(apb2).modify(|_, w| w.sdmmcrst().set_bit());
+        apb2rstr(apb2).modify(|_, w| w.sdmmcrst().clear_bit());
+
+        // The card clock may be at most 8/3 of the APB2 clock.
+        let max_card_clock = clocks.pclk2().0 / 3 * 8;
+        config.data_clock = config.data_clock.min(max_card_clock);
+        config.high_speed_clock = config.high_speed_clock.min(max_card_clock);
+
+        Device::new(sdmmc, dma, pins, Hertz(frequency), config)
+    }
 
-        self.dma.ccr4.modify(|_, w| w.en().clear_bit());
-        self.sdmmc
-            .icr
-            .write(|w| unsafe { w.bits(STATUS_ERROR_MASK) });
-        self.state = State::Ready;
-        if status.dcrcfail().bit() {
-            Err(Other(CRCFail))
-        } else if status.dtimeout().bit() {
-            Err(Other(Timeout))
-        } else if status.rxoverr().bit() {
-            Err(Other(ReceiveOverrun))
-        } else if status.txunderr().bit() {
-            Err(Other(SendUnderrun))
-        } else if !status.dataend().bit() || !status.dbckend().bit() {
-            Err(Other(UnknownResult))
-        } else {
-            Ok(())
-        }
+    /// Recycle the object to get back the SDMMC and DMA peripherals. Panics if an operation is
+    /// still ongoing.
+    pub fn free(mut self) -> (stm32::SDMMC1, stm32::DMA2, P) {
+        self.reset();
+        let Registers { sdmmc, dma, pins } = self.host;
+        (sdmmc, dma, pins)
     }
 }
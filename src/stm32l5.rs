@@ -0,0 +1,32 @@
+//! The SDMMC1 peripheral of the STM32L5 family, through its non-secure or secure address.
+
+use core::ops::Deref;
+
+use crate::Sdmmc;
+
+const SDMMC1_BASE: usize = 0x420c_8000;
+const SEC_SDMMC1_BASE: usize = 0x520c_8000;
+
+impl<P: Deref> Sdmmc<P> {
+    /// Take control of SDMMC1, given as the `SDMMC1` or `SEC_SDMMC1` peripheral of a peripheral
+    /// access crate such as `stm32l5`. Fails and returns the peripheral if it is neither. The
+    /// pins PC12 (CK), PD2 (CMD) and PC8 to PC11 (D0 to D3) should be configured in alternate
+    /// function 12, and its clock enabled.
+    pub fn new(sdmmc: P) -> Result<Self, P> {
+        Sdmmc::with_peripheral(sdmmc, &[SDMMC1_BASE, SEC_SDMMC1_BASE], false)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sdmmc_v2::tests::Peripheral;
+
+    #[test]
+    fn only_sdmmc1() {
+        for base in [SDMMC1_BASE, SEC_SDMMC1_BASE] {
+            assert!(Sdmmc::new(Peripheral(base)).is_ok());
+        }
+        assert!(Sdmmc::new(Peripheral(0x420c_8400)).is_err());
+    }
+}
@@ -0,0 +1,47 @@
+//! The SDMMC1 and SDMMC2 peripherals of the STM32U5 family, through their non-secure or secure
+//! addresses.
+
+use core::ops::Deref;
+
+use crate::Sdmmc;
+
+const SDMMC1_BASE: usize = 0x420c_8000;
+const SDMMC2_BASE: usize = 0x420c_8c00;
+/// The secure addresses are the non-secure ones with bit 28 set.
+const SECURE: usize = 0x1000_0000;
+
+impl<P: Deref> Sdmmc<P> {
+    /// Take control of SDMMC1 or SDMMC2, given as a peripheral of a peripheral access crate such
+    /// as `stm32u5`, at its non-secure or secure address. Fails and returns the peripheral if it
+    /// is neither. The pins of SDMMC1 are PC12 (CK), PD2 (CMD) and PC8 to PC11 (D0 to D3) in
+    /// alternate function 12. The pins should be configured, and the clock of the peripheral
+    /// enabled.
+    pub fn new(sdmmc: P) -> Result<Self, P> {
+        let bases = [
+            SDMMC1_BASE,
+            SDMMC2_BASE,
+            SDMMC1_BASE | SECURE,
+            SDMMC2_BASE | SECURE,
+        ];
+        Sdmmc::with_peripheral(sdmmc, &bases, false)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sdmmc_v2::tests::Peripheral;
+
+    #[test]
+    fn only_sdmmc1_and_sdmmc2() {
+        for base in [
+            SDMMC1_BASE,
+            SDMMC2_BASE,
+            SDMMC1_BASE | SECURE,
+            SDMMC2_BASE | SECURE,
+        ] {
+            assert!(Sdmmc::new(Peripheral(base)).is_ok());
+        }
+        assert!(Sdmmc::new(Peripheral(0x420c_8400)).is_err());
+    }
+}
@@ -0,0 +1,157 @@
+use crate::Error;
+
+/// Whether SWITCH_FUNC only checks if functions are available or actually switches to them.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum SwitchMode {
+    Check = 0,
+    Switch = 1,
+}
+
+impl SwitchMode {
+    /// The SWITCH_FUNC argument that selects `function` in `group` and leaves the other groups
+    /// unchanged. Groups are numbered 1 to 6, and function 0xf means no change.
+    pub(crate) fn argument(self, group: u8, function: u8) -> Result<u32, Error> {
+        if !(1..=6).contains(&group) || function >= 0xf {
+            return Err(Error::InvalidValue);
+        }
+
+        let shift = (group - 1) * 4;
+        Ok((self as u32) << 31 | 0x00ff_ffff & !(0xf << shift) | (function as u32) << shift)
+    }
+}
+
+/// The function group that selects the bus speed mode.
+pub const ACCESS_MODE_GROUP: u8 = 1;
+/// The default speed function of the access mode group, up to 25 MHz.
+pub const DEFAULT_SPEED: u8 = 0;
+/// The high speed function of the access mode group, up to 50 MHz.
+pub const HIGH_SPEED: u8 = 1;
+
+/// The status data block returned by SWITCH_FUNC. Function groups are numbered 1 to 6, the
+/// accessors panic for other group numbers.
+#[derive(Copy, Clone)]
+pub struct SwitchStatus(pub(crate) [u8; 64]);
+
+impl core::fmt::Debug for SwitchStatus {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "SwitchStatus(")?;
+        write!(f, "max_current={}mA, ", self.max_current())?;
+        write!(f, "version={}, ", self.version())?;
+        for group in 1..=6 {
+            write!(
+                f,
+                "group{}=({:#06x}, {:x?}), ",
+                group,
+                self.supported(group),
+                self.selected(group)
+            )?;
+        }
+        write!(f, "busy={:#06x})", self.busy(ACCESS_MODE_GROUP))?;
+        Ok(())
+    }
+}
+
+impl SwitchStatus {
+    fn group_offset(group: u8) -> usize {
+        assert!(
+            (1..=6).contains(&group),
+            "function groups are numbered 1 to 6"
+        );
+        group as usize - 1
+    }
+
+    /// The maximum current consumption in mA with the selected functions.
+    pub fn max_current(&self) -> u16 {
+        u16::from_be_bytes([self.0[0], self.0[1]])
+    }
+
+    /// The version of the status data structure.
+    pub fn version(&self) -> u8 {
+        self.0[17]
+    }
+
+    /// The functions supported in a group as a bit mask.
+    pub fn supported(&self, group: u8) -> u16 {
+        let offset = 12 - 2 * Self::group_offset(group);
+        u16::from_be_bytes([self.0[offset], self.0[offset + 1]])
+    }
+
+    /// Whether a function in a group is supported.
+    pub fn is_supported(&self, group: u8, function: u8) -> bool {
+        function < 16 && (self.supported(group) >> function) & 1 != 0
+    }
+
+    /// The function that is or will be selected in a group. Returns an error if the requested
+    /// function could not be switched to.
+    pub fn selected(&self, group: u8) -> Result<u8, Error> {
+        let offset = Self::group_offset(group);
+        match (self.0[16 - offset / 2] >> (4 * (offset % 2))) & 0xf {
+            0xf => Err(Error::InvalidValue),
+            function => Ok(function),
+        }
+    }
+
+    /// The functions in a group that are busy as a bit mask. Only available from version 1.
+    pub fn busy(&self, group: u8) -> u16 {
+        if self.version() == 0 {
+            return 0;
+        }
+
+        let offset = 28 - 2 * Self::group_offset(group);
+        u16::from_be_bytes([self.0[offset], self.0[offset + 1]])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The status of a high speed card checking the high speed function.
+    fn check_high_speed() -> SwitchStatus {
+        let mut status = [0; 64];
+        status[..18].copy_from_slice(&[
+            0x00, 0x64, 0x80, 0x01, 0x80, 0x01, 0x80, 0x01, 0x80, 0x01, 0x80, 0x01, 0x80, 0x03,
+            0x00, 0x00, 0x01, 0x01,
+        ]);
+        status[28..30].copy_from_slice(&[0x00, 0x02]);
+        SwitchStatus(status)
+    }
+
+    #[test]
+    fn decode() {
+        let status = check_high_speed();
+        assert_eq!(status.max_current(), 100);
+        assert_eq!(status.version(), 1);
+        assert_eq!(status.supported(ACCESS_MODE_GROUP), 0x8003);
+        assert!(status.is_supported(ACCESS_MODE_GROUP, DEFAULT_SPEED));
+        assert!(status.is_supported(ACCESS_MODE_GROUP, HIGH_SPEED));
+        assert!(!status.is_supported(ACCESS_MODE_GROUP, 2));
+        assert!(!status.is_supported(ACCESS_MODE_GROUP, 16));
+        assert_eq!(status.selected(ACCESS_MODE_GROUP), Ok(HIGH_SPEED));
+        assert_eq!(status.busy(ACCESS_MODE_GROUP), 0x0002);
+        for group in 2..=6 {
+            assert_eq!(status.supported(group), 0x8001);
+            assert_eq!(status.selected(group), Ok(0));
+            assert_eq!(status.busy(group), 0);
+        }
+    }
+
+    #[test]
+    fn unsupported_function() {
+        let mut status = check_high_speed();
+        status.0[16] = 0x0f;
+        status.0[14] = 0x20;
+        assert_eq!(status.selected(ACCESS_MODE_GROUP), Err(Error::InvalidValue));
+        assert_eq!(status.selected(6), Ok(2));
+
+        // Version 0 of the structure has no busy status.
+        status.0[17] = 0;
+        assert_eq!(status.busy(ACCESS_MODE_GROUP), 0);
+    }
+
+    #[test]
+    #[should_panic]
+    fn group_out_of_range() {
+        check_high_speed().supported(7);
+    }
+}
@@ -0,0 +1,225 @@
+use crate::{Block, BlockIndex, CardHost, Error};
+#[cfg(feature = "alloc")]
+use alloc::boxed::Box;
+use nb::block;
+use nb::Error::{Other, WouldBlock};
+
+/// Memory that can be handed to the DMA for the duration of a transfer.
+///
+/// # Safety
+///
+/// Implementors must guarantee that the blocks returned by `blocks` stay valid and at the same
+/// address for as long as the buffer exists, even when the buffer value itself is moved, and that
+/// nothing else writes to them while the buffer is held by a `Transfer`. A `Transfer` can be
+/// forgotten with `mem::forget` while the DMA is still running, so the memory may not be freed
+/// without dropping the buffer either. Memory that is `'static` or owned by a box meets these
+/// requirements.
+pub unsafe trait BlockBuffer: 'static {
+    /// The blocks to transfer.
+    fn blocks(&self) -> &[Block];
+}
+
+/// Memory that the DMA can write to for the duration of a transfer.
+///
+/// # Safety
+///
+/// The same requirements as for `BlockBuffer` apply. In addition, nothing else may read from the
+/// blocks while the buffer is held by a `Transfer`.
+pub unsafe trait BlockBufferMut: BlockBuffer {
+    /// The blocks to transfer into.
+    fn blocks_mut(&mut self) -> &mut [Block];
+}
+
+unsafe impl BlockBuffer for &'static Block {
+    fn blocks(&self) -> &[Block] {
+        core::slice::from_ref(*self)
+    }
+}
+
+unsafe impl BlockBuffer for &'static [Block] {
+    fn blocks(&self) -> &[Block] {
+        self
+    }
+}
+
+unsafe impl<const N: usize> BlockBuffer for &'static [Block; N] {
+    fn blocks(&self) -> &[Block] {
+        &self[..]
+    }
+}
+
+unsafe impl BlockBuffer for &'static mut Block {
+    fn blocks(&self) -> &[Block] {
+        core::slice::from_ref(&**self)
+    }
+}
+
+unsafe impl BlockBufferMut for &'static mut Block {
+    fn blocks_mut(&mut self) -> &mut [Block] {
+        core::slice::from_mut(*self)
+    }
+}
+
+unsafe impl BlockBuffer for &'static mut [Block] {
+    fn blocks(&self) -> &[Block] {
+        self
+    }
+}
+
+unsafe impl BlockBufferMut for &'static mut [Block] {
+    fn blocks_mut(&mut self) -> &mut [Block] {
+        self
+    }
+}
+
+unsafe impl<const N: usize> BlockBuffer for &'static mut [Block; N] {
+    fn blocks(&self) -> &[Block] {
+        &self[..]
+    }
+}
+
+unsafe impl<const N: usize> BlockBufferMut for &'static mut [Block; N] {
+    fn blocks_mut(&mut self) -> &mut [Block] {
+        &mut self[..]
+    }
+}
+
+#[cfg(feature = "alloc")]
+unsafe impl BlockBuffer for Box<Block> {
+    fn blocks(&self) -> &[Block] {
+        core::slice::from_ref(&**self)
+    }
+}
+
+#[cfg(feature = "alloc")]
+unsafe impl BlockBufferMut for Box<Block> {
+    fn blocks_mut(&mut self) -> &mut [Block] {
+        core::slice::from_mut(&mut **self)
+    }
+}
+
+#[cfg(feature = "alloc")]
+unsafe impl BlockBuffer for Box<[Block]> {
+    fn blocks(&self) -> &[Block] {
+        self
+    }
+}
+
+#[cfg(feature = "alloc")]
+unsafe impl BlockBufferMut for Box<[Block]> {
+    fn blocks_mut(&mut self) -> &mut [Block] {
+        self
+    }
+}
+
+#[cfg(feature = "alloc")]
+unsafe impl<const N: usize> BlockBuffer for Box<[Block; N]> {
+    fn blocks(&self) -> &[Block] {
+        &self[..]
+    }
+}
+
+#[cfg(feature = "alloc")]
+unsafe impl<const N: usize> BlockBufferMut for Box<[Block; N]> {
+    fn blocks_mut(&mut self) -> &mut [Block] {
+        &mut self[..]
+    }
+}
+
+/// A read or write operation in progress. The buffer can only be taken back once the card host
+/// reports that the operation has finished.
+///
+/// Dropping a transfer before it has finished blocks until the card host reports that the
+/// operation has finished, so that the buffer is never dropped while the DMA still uses it.
+pub struct Transfer<'h, H: CardHost + ?Sized, B> {
+    host: &'h mut H,
+    // Only taken by `wait`, which consumes the transfer.
+    buffer: Option<B>,
+    outcome: Option<Result<(), Error>>,
+}
+
+impl<'h, H: CardHost + ?Sized, B: BlockBuffer> Transfer<'h, H, B> {
+    /// Start writing the blocks in `buffer` to the card, starting at `address`.
+    pub(crate) fn write(
+        host: &'h mut H,
+        buffer: B,
+        address: BlockIndex,
+    ) -> Result<Self, (Error, B)> {
+        if buffer.blocks().is_empty() {
+            return Err((Error::InvalidValue, buffer));
+        }
+
+        // The buffer is kept alive and unchanged until the transfer has finished.
+        match unsafe { host.write_blocks(buffer.blocks(), address) } {
+            Ok(()) => Ok(Transfer {
+                host,
+                buffer: Some(buffer),
+                outcome: None,
+            }),
+            Err(e) => Err((e, buffer)),
+        }
+    }
+
+    /// Check the result of the operation.
+    pub fn result(&mut self) -> nb::Result<(), Error> {
+        if let Some(outcome) = self.outcome {
+            return outcome.map_err(Other);
+        }
+
+        match self.host.result() {
+            Err(WouldBlock) => Err(WouldBlock),
+            Err(Other(e)) => {
+                self.outcome = Some(Err(e));
+                Err(Other(e))
+            }
+            Ok(()) => {
+                self.outcome = Some(Ok(()));
+                Ok(())
+            }
+        }
+    }
+
+    /// Block until the operation has finished and return the buffer.
+    pub fn wait(mut self) -> Result<B, (Error, B)> {
+        let result = block!(self.result());
+        let buffer = self.buffer.take().unwrap();
+        match result {
+            Ok(()) => Ok(buffer),
+            Err(e) => Err((e, buffer)),
+        }
+    }
+}
+
+impl<'h, H: CardHost + ?Sized, B> Drop for Transfer<'h, H, B> {
+    fn drop(&mut self) {
+        // The card host gives up with a timeout error if the card stops responding.
+        if self.outcome.is_none() {
+            let _ = block!(self.host.result());
+        }
+    }
+}
+
+impl<'h, H: CardHost + ?Sized, B: BlockBufferMut> Transfer<'h, H, B> {
+    /// Start reading from the card at `address` into `buffer`.
+    pub(crate) fn read(
+        host: &'h mut H,
+        mut buffer: B,
+        address: BlockIndex,
+    ) -> Result<Self, (Error, B)> {
+        let result = match buffer.blocks_mut() {
+            // The buffer is kept alive and untouched until the transfer has finished.
+            [] => Err(Error::InvalidValue),
+            [block] => unsafe { host.read_block(block, address) },
+            blocks => unsafe { host.read_blocks(blocks, address) },
+        };
+
+        match result {
+            Ok(()) => Ok(Transfer {
+                host,
+                buffer: Some(buffer),
+                outcome: None,
+            }),
+            Err(e) => Err((e, buffer)),
+        }
+    }
+}
@@ -0,0 +1,131 @@
+//! Runs the device against a mocked host controller that answers like a high capacity card.
+
+use stm32_sdmmc::{
+    Block, BusWidth, CardHost, Config, DataDirection, Device, HostController, HostStatus, Response,
+    BLOCK_SIZE,
+};
+
+/// Card status in the transfer state and ready for data.
+const TRANSFER_STATUS: u32 = 4 << 9 | 1 << 8;
+/// CSD version 2 with a C_SIZE of 0x3b37.
+const CSD: [u32; 4] = [0x400e_0032, 0x5b59_0000, 0x3b37_7f80, 0x0a40_4000];
+
+#[derive(Default)]
+struct MockHost {
+    response: [u32; 4],
+    index: u8,
+    status: u32,
+    buffer: Option<(*mut u8, usize)>,
+    pending: Option<usize>,
+    /// The last command starts a data transfer.
+    data_command: bool,
+    commands: Vec<(u8, u32)>,
+    /// The number of SEND_STATUS commands for which the card reports programming.
+    busy: u32,
+}
+
+impl HostController for MockHost {
+    fn configure(&mut self, _clock_divider: u16, _bus_width: BusWidth, _data_timeout: u32) {}
+
+    fn send_command(&mut self, index: u8, arg: u32, response: Response, data: DataDirection) {
+        self.data_command = data != DataDirection::None;
+        let app = matches!(self.commands.last(), Some((55, _)));
+        self.commands.push((index, arg));
+        self.index = index;
+        self.response = [0; 4];
+        self.response[0] = match (app, index) {
+            (_, 0) => 0,
+            (_, 8) => arg,
+            (_, 55) => TRANSFER_STATUS | 1 << 5,
+            (true, 41) => 0xc0ff_8000,
+            (_, 3) => 0x1234_0000 | 0x0500,
+            (false, 13) if self.busy > 0 => {
+                self.busy -= 1;
+                7 << 9
+            }
+            _ => TRANSFER_STATUS,
+        };
+        if index == 9 {
+            self.response = CSD;
+        }
+
+        self.status = match response {
+            Response::None => HostStatus::CMDSENT,
+            _ => HostStatus::CMDREND,
+        };
+    }
+
+    fn response_index(&self) -> u8 {
+        self.index
+    }
+
+    fn response(&self) -> [u32; 4] {
+        self.response
+    }
+
+    fn status(&self) -> HostStatus {
+        HostStatus(self.status)
+    }
+
+    fn clear_status(&mut self) {
+        self.status = 0;
+        // The data arrives once the response to the read command has been handled.
+        let pending = match self.data_command {
+            true => self.pending.take(),
+            false => None,
+        };
+        if let Some(len) = pending {
+            let (buffer, dma_len) = self.buffer.expect("data path started without DMA");
+            assert_eq!(len, dma_len);
+            let data = unsafe { std::slice::from_raw_parts_mut(buffer, len) };
+            for (i, byte) in data.iter_mut().enumerate() {
+                *byte = i as u8;
+            }
+            self.status = HostStatus::DATAEND | HostStatus::DBCKEND;
+        }
+    }
+
+    fn start_data(&mut self, len: usize, _block_size: usize, direction: DataDirection) {
+        assert_eq!(direction, DataDirection::Read);
+        self.pending = Some(len);
+    }
+
+    unsafe fn start_dma(&mut self, buffer: *mut u8, len: usize, _direction: DataDirection) {
+        self.buffer = Some((buffer, len));
+    }
+
+    fn stop_dma(&mut self) {
+        self.buffer = None;
+    }
+
+    fn reset(&mut self) {}
+}
+
+#[test]
+fn init_and_read_block() {
+    let mut device = Device::with_host(MockHost::default(), Config::default());
+    nb::block!(device.init_card()).unwrap();
+    assert_eq!(device.card_size(), Ok(0x3b38 << 10));
+
+    static mut BLOCK: Block = [0; BLOCK_SIZE];
+    let block = unsafe { &mut *core::ptr::addr_of_mut!(BLOCK) };
+    let block = device.start_read(block, 7).map_err(|(e, _)| e).unwrap();
+    let block = block.wait().map_err(|(e, _)| e).unwrap();
+    assert!(block.iter().enumerate().all(|(i, &byte)| byte == i as u8));
+}
+
+#[test]
+fn select_card_waits_for_busy() {
+    let host = MockHost {
+        busy: 2,
+        ..MockHost::default()
+    };
+    let mut device = Device::with_host(host, Config::default());
+    nb::block!(device.init_card()).unwrap();
+
+    // SELECT_CARD has an R1b response, so the card is polled until it is no longer busy.
+    let commands = &device.host().commands;
+    let select = commands.iter().position(|&(index, _)| index == 7).unwrap();
+    let indices: Vec<u8> = commands[select..select + 5].iter().map(|c| c.0).collect();
+    assert_eq!(indices, [7, 13, 13, 13, 16]);
+}
@@ -0,0 +1,476 @@
+//! Runs the device end to end against simulated cards.
+
+use std::sync::atomic::{AtomicU32, Ordering};
+use stm32_sdmmc::sim::{Card, CardConfig, Simulator};
+use stm32_sdmmc::{
+    Block, BusWidth, CardEvent, CardHost, CardVersion, Config, DataDirection, Device, Error,
+    HostController, HostStatus, Response, SwitchMode, WriteProtection, ACCESS_MODE_GROUP,
+    BLOCK_SIZE, HIGH_SPEED,
+};
+
+/// A simulator that keeps the default `MAX_BLOCKS`, so long transfers are not split up.
+struct Unchunked(Simulator);
+
+impl HostController for Unchunked {
+    fn configure(&mut self, clock_divider: u16, bus_width: BusWidth, data_timeout: u32) {
+        self.0.configure(clock_divider, bus_width, data_timeout)
+    }
+
+    fn send_command(&mut self, index: u8, arg: u32, response: Response, data: DataDirection) {
+        self.0.send_command(index, arg, response, data)
+    }
+
+    fn response_index(&self) -> u8 {
+        self.0.response_index()
+    }
+
+    fn response(&self) -> [u32; 4] {
+        self.0.response()
+    }
+
+    fn status(&self) -> HostStatus {
+        self.0.status()
+    }
+
+    fn clear_status(&mut self) {
+        self.0.clear_status()
+    }
+
+    fn start_data(&mut self, len: usize, block_size: usize, direction: DataDirection) {
+        self.0.start_data(len, block_size, direction)
+    }
+
+    unsafe fn start_dma(&mut self, buffer: *mut u8, len: usize, direction: DataDirection) {
+        self.0.start_dma(buffer, len, direction)
+    }
+
+    fn stop_dma(&mut self) {
+        self.0.stop_dma()
+    }
+
+    fn reset(&mut self) {
+        self.0.reset()
+    }
+}
+
+fn device(config: CardConfig) -> Device<Simulator> {
+    let simulator = Simulator::new(Some(Card::new(config)));
+    let mut device = Device::with_host(simulator, Config::default());
+    nb::block!(device.init_card()).unwrap();
+    device
+}
+
+fn buffer(count: usize) -> &'static mut [Block] {
+    Box::leak(vec![[0; BLOCK_SIZE]; count].into_boxed_slice())
+}
+
+fn pattern(blocks: &mut [Block], seed: u8) {
+    for (i, block) in blocks.iter_mut().enumerate() {
+        for (j, byte) in block.iter_mut().enumerate() {
+            *byte = seed ^ (i * 7 + j) as u8;
+        }
+    }
+}
+
+fn card_data(device: &Device<Simulator>) -> &[u8] {
+    device.host().card().unwrap().data()
+}
+
+fn write(device: &mut Device<Simulator>, blocks: &'static mut [Block], address: u32) {
+    let transfer = device.start_write(&*blocks, address).map_err(|(e, _)| e);
+    transfer.unwrap().wait().map_err(|(e, _)| e).unwrap();
+}
+
+fn read(device: &mut Device<Simulator>, count: usize, address: u32) -> &'static mut [Block] {
+    let transfer = device
+        .start_read(buffer(count), address)
+        .map_err(|(e, _)| e);
+    transfer.unwrap().wait().map_err(|(e, _)| e).unwrap()
+}
+
+fn single_blocks(config: CardConfig) {
+    let blocks = match config.version {
+        CardVersion::V2HC => 2048,
+        _ => 1024,
+    };
+    let mut device = device(config);
+    assert_eq!(device.card_size(), Ok(blocks));
+    assert_eq!(device.card_id().unwrap().product_name(), *b"SIMUL");
+
+    let data = buffer(1);
+    pattern(data, 0x5a);
+    let expected = data[0];
+    write(&mut device, data, 3);
+    assert_eq!(
+        card_data(&device)[3 * BLOCK_SIZE..4 * BLOCK_SIZE],
+        expected[..]
+    );
+    assert_eq!(read(&mut device, 1, 3)[0], expected);
+
+    let past_end = device.card_size().unwrap();
+    let result = device.start_read(buffer(1), past_end).map(|_| ());
+    assert_eq!(result.map_err(|(e, _)| e), Err(Error::OutOfRange));
+}
+
+#[test]
+fn v1_card() {
+    single_blocks(CardConfig::v1(1024));
+}
+
+#[test]
+fn v2_standard_capacity_card() {
+    single_blocks(CardConfig::v2_sc(1024));
+}
+
+#[test]
+fn v2_high_capacity_card() {
+    single_blocks(CardConfig::v2_hc(2048));
+}
+
+#[test]
+fn multiple_blocks() {
+    for config in [
+        CardConfig::v1(1024),
+        CardConfig::v2_sc(1024),
+        CardConfig::v2_hc(2048),
+    ] {
+        let mut device = device(config);
+        let data = buffer(600);
+        pattern(data, 0xc3);
+        let expected = data.concat();
+        write(&mut device, data, 100);
+        assert_eq!(
+            card_data(&device)[100 * BLOCK_SIZE..700 * BLOCK_SIZE],
+            expected[..]
+        );
+        assert_eq!(read(&mut device, 600, 100).concat(), expected);
+
+        let last = device.card_size().unwrap() - 1;
+        let result = match device.start_read(buffer(2), last) {
+            Ok(transfer) => transfer.wait().map(|_| ()).map_err(|(e, _)| e),
+            Err((e, _)) => Err(e),
+        };
+        assert_eq!(result, Err(Error::OutOfRange));
+        assert_eq!(read(&mut device, 2, 0).concat(), card_data(&device)[..1024]);
+    }
+}
+
+#[test]
+fn boxed_buffers() {
+    let mut device = device(CardConfig::v2_hc(2048));
+    let mut data = Box::new([[0; BLOCK_SIZE]; 3]);
+    pattern(&mut data[..], 0x3c);
+    let expected = data.concat();
+    let transfer = device.start_write(data, 5).map_err(|(e, _)| e);
+    transfer.unwrap().wait().map_err(|(e, _)| e).unwrap();
+
+    // Dropping the transfer waits for the read, so the device is ready again afterwards.
+    let buffer = vec![[0; BLOCK_SIZE]; 3].into_boxed_slice();
+    drop(device.start_read(buffer, 5).map_err(|(e, _)| e).unwrap());
+    let transfer = device.start_read(Box::new([0; BLOCK_SIZE]), 6);
+    let block = transfer.map_err(|(e, _)| e).unwrap().wait();
+    assert_eq!(
+        block.map_err(|(e, _)| e).unwrap()[..],
+        expected[BLOCK_SIZE..2 * BLOCK_SIZE]
+    );
+}
+
+#[test]
+fn default_max_blocks() {
+    let simulator = Simulator::new(Some(Card::new(CardConfig::v2_hc(2048))));
+    let mut device = Device::with_host(Unchunked(simulator), Config::default());
+    nb::block!(device.init_card()).unwrap();
+    let data = &mut device.host_mut().0.card_mut().unwrap().data_mut()[..600 * BLOCK_SIZE];
+    for (i, byte) in data.iter_mut().enumerate() {
+        *byte = (i * 7) as u8;
+    }
+
+    let transfer = device.start_read(buffer(600), 0).map_err(|(e, _)| e);
+    let blocks = transfer.unwrap().wait().map_err(|(e, _)| e).unwrap();
+    assert_eq!(
+        blocks.concat(),
+        device.host().0.card().unwrap().data()[..600 * BLOCK_SIZE]
+    );
+}
+
+#[test]
+fn stop_transmission() {
+    // Version 1 cards do not support SET_BLOCK_COUNT.
+    let mut device = device(CardConfig::v1(1024));
+    let data = buffer(4);
+    pattern(data, 0x3c);
+    let expected = data.concat();
+    write(&mut device, data, 8);
+    assert_eq!(read(&mut device, 4, 8).concat(), expected);
+    assert!(matches!(
+        device.host().card().unwrap().state(),
+        stm32_sdmmc::CardState::Transmit
+    ));
+}
+
+#[test]
+fn erase() {
+    let mut device = device(CardConfig::v2_hc(2048));
+    device.host_mut().card_mut().unwrap().data_mut().fill(0xaa);
+    device.erase(10, 19).unwrap();
+    nb::block!(device.result()).unwrap();
+    let data = card_data(&device);
+    assert!(data[..10 * BLOCK_SIZE].iter().all(|&byte| byte == 0xaa));
+    assert!(data[10 * BLOCK_SIZE..20 * BLOCK_SIZE]
+        .iter()
+        .all(|&byte| byte == 0));
+    assert!(data[20 * BLOCK_SIZE..].iter().all(|&byte| byte == 0xaa));
+}
+
+#[test]
+fn write_protect_switch() {
+    let simulator = Simulator::new(Some(Card::new(CardConfig::v2_hc(2048))));
+    let switch = simulator.write_protect();
+    let mut device = Device::with_host(simulator, Config::default()).with_write_protect(switch);
+    nb::block!(device.init_card()).unwrap();
+    assert!(!device.write_protection().is_protected());
+
+    device.host_mut().set_write_protect(true);
+    assert_eq!(
+        device.write_protection(),
+        WriteProtection {
+            switch: true,
+            ..WriteProtection::default()
+        }
+    );
+    let data = buffer(1);
+    let result = device.start_write(&*data, 0).map(|_| ());
+    assert_eq!(result.map_err(|(e, _)| e), Err(Error::WriteProtected));
+    assert_eq!(device.erase(0, 1), Err(Error::WriteProtected));
+    assert_eq!(device.erase_card(), Err(Error::WriteProtected));
+
+    device.host_mut().set_write_protect(false);
+    device.erase(0, 1).unwrap();
+    nb::block!(device.result()).unwrap();
+}
+
+#[test]
+fn csd_write_protection() {
+    let mut config = CardConfig::v2_hc(2048);
+    config.csd[3] |= 1 << 12;
+    let mut device = device(config);
+    assert!(device.write_protection().temporary);
+    assert_eq!(device.erase_card(), Err(Error::WriteProtected));
+}
+
+#[test]
+fn registers_and_speed() {
+    let config = Config {
+        bus_width: BusWidth::Bits4,
+        negotiate_bus_width: true,
+        ..Config::default()
+    };
+    let simulator = Simulator::new(Some(Card::new(CardConfig::v2_hc(2048))));
+    let mut device = Device::with_host(simulator, config);
+    nb::block!(device.init_card()).unwrap();
+    assert!(matches!(device.bus_width(), BusWidth::Bits4));
+    assert_eq!(device.host().card().unwrap().bus_width(), 4);
+    assert_eq!(device.read_scr().unwrap().spec_version(), Ok((3, 0)));
+    assert!(device.read_sd_status().is_ok());
+
+    device.enable_high_speed().unwrap();
+    assert!(device.host().card().unwrap().high_speed());
+}
+
+#[test]
+fn failed_register_read_stops_dma() {
+    let mut device = device(CardConfig::v2_hc(2048));
+    device.host_mut().remove();
+    assert_eq!(device.read_scr().map(|_| ()), Err(Error::Timeout));
+    assert!(!device.host().dma_enabled());
+    let status = device.switch_function(SwitchMode::Check, ACCESS_MODE_GROUP, HIGH_SPEED);
+    assert_eq!(status.map(|_| ()), Err(Error::Timeout));
+    assert!(!device.host().dma_enabled());
+}
+
+#[test]
+fn no_card() {
+    let mut device = Device::with_host(Simulator::new(None), Config::default());
+    assert_eq!(nb::block!(device.init_card()), Err(Error::NoCard));
+}
+
+#[test]
+fn card_detect() {
+    let simulator = Simulator::new(Some(Card::new(CardConfig::v2_hc(2048))));
+    let switch = simulator.card_detect();
+    let mut device = Device::with_host(simulator, Config::default()).with_card_detect(switch);
+    assert!(device.card_present());
+    nb::block!(device.init_card()).unwrap();
+
+    // Leave a read running when the card is pulled out. Forgetting the transfer keeps its drop
+    // from waiting for the read.
+    let transfer = device.start_read(buffer(600), 0).map_err(|(e, _)| e);
+    std::mem::forget(transfer.unwrap());
+    let card = device.host_mut().remove().unwrap();
+    assert_eq!(device.poll_card_detect(), None);
+    assert_eq!(device.poll_card_detect(), None);
+    assert_eq!(device.poll_card_detect(), Some(CardEvent::Removed));
+    assert!(!device.card_present());
+    assert_eq!(device.result(), Err(nb::Error::Other(Error::Uninitialized)));
+    assert_eq!(nb::block!(device.init_card()), Err(Error::NoCard));
+
+    device.host_mut().insert(card);
+    let event = (0..3).find_map(|_| device.poll_card_detect());
+    assert_eq!(event, Some(CardEvent::Inserted));
+    nb::block!(device.init_card()).unwrap();
+    assert_eq!(device.card_size(), Ok(2048));
+}
+
+#[test]
+fn power_cycle_recovery() {
+    let mut card = Card::new(CardConfig::v2_hc(2048));
+    card.wedge();
+    let simulator = Simulator::new(Some(card));
+    let power = simulator.power();
+    let config = || Config {
+        op_cond_polls: 10,
+        power_cycle_after: 2,
+        ..Config::default()
+    };
+    let mut device = Device::with_host(simulator, config()).with_power(power);
+    assert_eq!(nb::block!(device.init_card()), Err(Error::Timeout));
+    // The second failure power cycles the card, after which it initializes.
+    nb::block!(device.init_card()).unwrap();
+    assert_eq!(device.card_size(), Ok(2048));
+
+    // Without a power switch the card stays wedged.
+    let mut card = Card::new(CardConfig::v2_hc(2048));
+    card.wedge();
+    let simulator = Simulator::new(Some(card));
+    let mut device = Device::with_host(simulator, config());
+    for _ in 0..3 {
+        assert_eq!(nb::block!(device.init_card()), Err(Error::Timeout));
+    }
+}
+
+#[test]
+fn kernel_clock() {
+    let config = Config {
+        data_clock: 100_000_000,
+        ..Config::default()
+    };
+    let simulator = Simulator::new(Some(Card::new(CardConfig::v2_hc(2048))));
+    let mut device = Device::with_host(simulator, config).with_kernel_clock(48_000_000);
+    // Identification runs at no more than 400 kHz.
+    assert!(device.init_card().is_err());
+    assert_eq!(device.host().clock_divider(), 120);
+    assert_eq!(device.card_clock(), Some(400_000));
+
+    // The data clock is capped at the 25 MHz TRAN_SPEED of the card.
+    nb::block!(device.init_card()).unwrap();
+    assert_eq!(device.card_clock(), Some(24_000_000));
+
+    device.enable_high_speed().unwrap();
+    assert_eq!(device.card_clock(), Some(48_000_000));
+
+    // A card with a TRAN_SPEED of 20 MHz.
+    let mut config = CardConfig::v2_hc(2048);
+    config.csd[0] = config.csd[0] & !0x7f | 0x2a;
+    let simulator = Simulator::new(Some(Card::new(config)));
+    let mut device = Device::with_host(simulator, Config::default()).with_kernel_clock(48_000_000);
+    nb::block!(device.init_card()).unwrap();
+    assert_eq!(device.card_clock(), Some(16_000_000));
+    assert_eq!(device.host().clock_divider(), 3);
+}
+
+#[test]
+fn data_timeouts() {
+    let simulator = Simulator::new(Some(Card::new(CardConfig::v2_hc(2048))));
+    let mut device = Device::with_host(simulator, Config::default()).with_kernel_clock(48_000_000);
+    nb::block!(device.init_card()).unwrap();
+
+    // 100 times the 1 ms access time, 100 ms at 24 MHz.
+    read(&mut device, 1, 0);
+    assert_eq!(device.host().transfer_timeout(), 2_400_000);
+    // Four times that for writes, capped at 250 ms.
+    write(&mut device, buffer(1), 0);
+    assert_eq!(device.host().transfer_timeout(), 6_000_000);
+
+    // The erase timing in the SD Status allows two seconds for one AU.
+    device.erase(10, 19).unwrap();
+    nb::block!(device.result()).unwrap();
+    // At 100 kHz that is 200_000 card clock cycles, which the SEND_STATUS polls of at least 96
+    // cycles each use up after 2084 polls.
+    for (busy_polls, result) in [(2084, Ok(())), (2085, Err(Error::Timeout))] {
+        let mut config = CardConfig::v2_hc(2048);
+        config.busy_polls = busy_polls;
+        let simulator = Simulator::new(Some(Card::new(config)));
+        let mut device = Device::with_host(simulator, Config::default()).with_kernel_clock(100_000);
+        nb::block!(device.init_card()).unwrap();
+        device.erase(10, 19).unwrap();
+        assert_eq!(nb::block!(device.result()), result);
+    }
+
+    // A card with an access time of 100 us.
+    let mut config = CardConfig::v2_hc(2048);
+    config.csd[0] = config.csd[0] & !0x00ff_0000 | 0x0d << 16;
+    let simulator = Simulator::new(Some(Card::new(config)));
+    let mut device = Device::with_host(simulator, Config::default()).with_kernel_clock(48_000_000);
+    nb::block!(device.init_card()).unwrap();
+    read(&mut device, 1, 0);
+    assert_eq!(device.host().transfer_timeout(), 240_000);
+    write(&mut device, buffer(1), 0);
+    assert_eq!(device.host().transfer_timeout(), 960_000);
+}
+
+#[test]
+fn erase_timeout() {
+    let mut config = CardConfig::v2_hc(2048);
+    config.busy_polls = u32::MAX;
+    let simulator = Simulator::new(Some(Card::new(config)));
+    let mut device = Device::with_host(simulator, Config::default()).with_kernel_clock(100_000);
+    nb::block!(device.init_card()).unwrap();
+    device.erase(0, 0).unwrap();
+    assert_eq!(nb::block!(device.result()), Err(Error::Timeout));
+}
+
+#[test]
+fn write_timeout() {
+    // 250 ms at 100 kHz are 25_000 card clock cycles, which the SEND_STATUS polls of at least 96
+    // cycles each use up after 261 polls.
+    for (busy_polls, result) in [(261, None), (262, Some(Error::Timeout))] {
+        let mut config = CardConfig::v2_hc(2048);
+        config.busy_polls = busy_polls;
+        let simulator = Simulator::new(Some(Card::new(config)));
+        let mut device = Device::with_host(simulator, Config::default()).with_kernel_clock(100_000);
+        nb::block!(device.init_card()).unwrap();
+        let transfer = device.start_write(&*buffer(1), 0).map_err(|(e, _)| e);
+        assert_eq!(transfer.unwrap().wait().map_err(|(e, _)| e).err(), result);
+    }
+
+    // Without the kernel clock, the configured data timeout limits how long the card is polled.
+    let mut config = CardConfig::v2_hc(2048);
+    config.busy_polls = u32::MAX;
+    let mut device = device(config);
+    let transfer = device.start_write(&*buffer(1), 0).map_err(|(e, _)| e);
+    let result = transfer.unwrap().wait().map_err(|(e, _)| e);
+    assert_eq!(result.err(), Some(Error::Timeout));
+}
+
+/// A millisecond timer that advances by 100 ms every time it is read.
+fn coarse_timer() -> u32 {
+    static NOW: AtomicU32 = AtomicU32::new(0);
+    NOW.fetch_add(100, Ordering::Relaxed)
+}
+
+#[test]
+fn busy_timer() {
+    // With a timer, the two seconds the erase may take are up after 20 polls, although the polls
+    // at 24 MHz take far less.
+    for (busy_polls, result) in [(19, Ok(())), (20, Err(Error::Timeout))] {
+        let mut config = CardConfig::v2_hc(2048);
+        config.busy_polls = busy_polls;
+        let simulator = Simulator::new(Some(Card::new(config)));
+        let mut device = Device::with_host(simulator, Config::default())
+            .with_kernel_clock(48_000_000)
+            .with_timer(coarse_timer);
+        nb::block!(device.init_card()).unwrap();
+        device.erase(10, 19).unwrap();
+        assert_eq!(nb::block!(device.result()), result);
+    }
+}
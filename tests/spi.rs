@@ -0,0 +1,364 @@
+//! Runs the SPI mode card host against a mocked card that answers on the bus.
+
+use embedded_hal::blocking::spi::Transfer;
+use embedded_hal::digital::v2::OutputPin;
+use std::collections::{HashMap, VecDeque};
+use std::convert::{Infallible, TryInto};
+use stm32_sdmmc::{Block, CardHost, Error, SpiDevice, BLOCK_SIZE};
+
+/// CSD version 2 with a C_SIZE of 0x3b37.
+const CSD_V2: [u32; 4] = [0x400e_0032, 0x5b59_0000, 0x3b37_7f80, 0x0a40_4000];
+/// CSD version 1 of a card with 1024 blocks.
+const CSD_V1: [u32; 4] = [0x000e_0032, 0x5b59_0000, 0x4003_8000, 0x0a40_4000];
+
+fn crc7(data: &[u8]) -> u8 {
+    let mut crc = 0u8;
+    for &byte in data {
+        for bit in (0..8).rev() {
+            let feedback = (byte >> bit) & 1 ^ crc >> 6;
+            crc = (crc << 1) & 0x7f;
+            if feedback != 0 {
+                crc ^= 0x09;
+            }
+        }
+    }
+    crc
+}
+
+fn crc16(data: &[u8]) -> u16 {
+    let mut crc = 0u16;
+    for &byte in data {
+        crc ^= (byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                crc << 1 ^ 0x1021
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+/// A data block the card receives from the host.
+struct Receiving {
+    address: usize,
+    multiple: bool,
+    started: bool,
+    data: Vec<u8>,
+}
+
+#[derive(Default)]
+struct MockCard {
+    present: bool,
+    version1: bool,
+    high_capacity: bool,
+    idle_polls: u32,
+    idle: bool,
+    app_command: bool,
+    corrupt_reads: bool,
+    stuck_busy: bool,
+    frame: Vec<u8>,
+    out: VecDeque<u8>,
+    reading: Option<usize>,
+    receiving: Option<Receiving>,
+    blocks: HashMap<usize, Block>,
+    commands: Vec<(u8, u32)>,
+}
+
+impl MockCard {
+    fn new(version1: bool, high_capacity: bool) -> Self {
+        MockCard {
+            present: true,
+            version1,
+            high_capacity,
+            idle_polls: 3,
+            ..MockCard::default()
+        }
+    }
+
+    fn block(&self, index: usize) -> Block {
+        self.blocks.get(&index).copied().unwrap_or([0; BLOCK_SIZE])
+    }
+
+    fn block_index(&self, arg: u32) -> usize {
+        match self.high_capacity {
+            true => arg as usize,
+            false => {
+                assert_eq!(arg as usize % BLOCK_SIZE, 0);
+                arg as usize / BLOCK_SIZE
+            }
+        }
+    }
+
+    fn send_data(&mut self, data: &[u8]) {
+        let mut crc = crc16(data);
+        if self.corrupt_reads {
+            crc ^= 1;
+        }
+        self.out.push_back(0xff);
+        self.out.push_back(0xfe);
+        self.out.extend(data);
+        self.out.extend(crc.to_be_bytes());
+    }
+
+    fn register(&mut self, words: [u32; 4]) {
+        let bytes: Vec<u8> = words.iter().flat_map(|word| word.to_be_bytes()).collect();
+        self.send_data(&bytes);
+    }
+
+    fn command(&mut self, frame: &[u8]) {
+        let index = frame[0] & 0x3f;
+        let arg = u32::from_be_bytes([frame[1], frame[2], frame[3], frame[4]]);
+        self.commands.push((index, arg));
+        self.out.clear();
+        self.out.push_back(0xff);
+        if frame[5] != crc7(&frame[..5]) << 1 | 1 {
+            self.out.push_back(0x08);
+            return;
+        }
+
+        let app_command = std::mem::replace(&mut self.app_command, false);
+        let r1 = self.idle as u8;
+        match (app_command, index) {
+            (_, 0) => {
+                self.idle = true;
+                self.out.push_back(0x01);
+            }
+            (_, 8) if self.version1 => self.out.push_back(0x05),
+            (_, 8) => self.out.extend([r1, 0, 0, 0x01, 0xaa]),
+            (_, 55) => {
+                self.app_command = true;
+                self.out.push_back(r1);
+            }
+            (true, 41) => {
+                if self.idle_polls > 0 {
+                    self.idle_polls -= 1;
+                } else {
+                    self.idle = false;
+                }
+                self.out.push_back(self.idle as u8);
+            }
+            (_, 58) => {
+                let ccs = if self.high_capacity { 0x40 } else { 0 };
+                self.out.extend([r1, 0x80 | ccs, 0xff, 0x80, 0x00]);
+            }
+            (_, 9) => {
+                self.out.push_back(r1);
+                self.register(if self.high_capacity { CSD_V2 } else { CSD_V1 });
+            }
+            (_, 10) => {
+                self.out.push_back(r1);
+                self.register([0x0353_4453, 0x494d_554c, 0x1012_3456, 0x7800_1451]);
+            }
+            (_, 12) => {
+                self.reading = None;
+                self.out.extend([0xff, r1, 0x00, 0x00]);
+            }
+            (_, 13) => self.out.extend([r1, 0x00]),
+            (_, 17) => {
+                self.out.push_back(r1);
+                let block = self.block(self.block_index(arg));
+                self.send_data(&block);
+            }
+            (_, 18) => {
+                self.out.push_back(r1);
+                self.reading = Some(self.block_index(arg));
+            }
+            (_, 24) | (_, 25) => {
+                self.out.push_back(r1);
+                self.receiving = Some(Receiving {
+                    address: self.block_index(arg),
+                    multiple: index == 25,
+                    started: false,
+                    data: Vec::new(),
+                });
+            }
+            (_, 16) | (_, 32) | (_, 33) | (_, 59) => self.out.push_back(r1),
+            (_, 38) => self.out.extend([r1, 0x00, 0x00, 0x00]),
+            _ => self.out.push_back(r1 | 0x04),
+        }
+    }
+
+    fn receive(&mut self, byte: u8) {
+        if let Some(receiving) = self.receiving.as_mut() {
+            if !receiving.started {
+                match byte {
+                    0xfe | 0xfc => receiving.started = true,
+                    0xfd if receiving.multiple => {
+                        self.receiving = None;
+                        self.out.extend([0xff, 0x00, 0x00]);
+                    }
+                    _ => {}
+                }
+                return;
+            }
+
+            receiving.data.push(byte);
+            if receiving.data.len() < BLOCK_SIZE + 2 {
+                return;
+            }
+
+            let data = std::mem::take(&mut receiving.data);
+            let crc = u16::from_be_bytes([data[BLOCK_SIZE], data[BLOCK_SIZE + 1]]);
+            if crc != crc16(&data[..BLOCK_SIZE]) {
+                self.receiving = None;
+                self.out.push_back(0x0b);
+                return;
+            }
+
+            let address = receiving.address;
+            receiving.address += 1;
+            receiving.started = false;
+            if !receiving.multiple {
+                self.receiving = None;
+            }
+            self.blocks
+                .insert(address, data[..BLOCK_SIZE].try_into().unwrap());
+            self.out.extend([0x05, 0x00, 0x00]);
+            return;
+        }
+
+        if self.frame.is_empty() && byte & 0xc0 != 0x40 {
+            return;
+        }
+
+        self.frame.push(byte);
+        if self.frame.len() == 6 {
+            let frame = std::mem::take(&mut self.frame);
+            self.command(&frame);
+        }
+    }
+}
+
+impl Transfer<u8> for MockCard {
+    type Error = Infallible;
+
+    fn transfer<'w>(&mut self, words: &'w mut [u8]) -> Result<&'w [u8], Infallible> {
+        for word in words.iter_mut() {
+            if !self.present {
+                *word = 0xff;
+                continue;
+            }
+
+            if self.out.is_empty() && self.stuck_busy {
+                self.out.push_back(0x00);
+            } else if self.out.is_empty() {
+                if let Some(index) = self.reading {
+                    self.reading = Some(index + 1);
+                    let block = self.block(index);
+                    self.send_data(&block);
+                }
+            }
+
+            let out = self.out.pop_front().unwrap_or(0xff);
+            self.receive(*word);
+            *word = out;
+        }
+
+        Ok(words)
+    }
+}
+
+struct MockPin;
+
+impl OutputPin for MockPin {
+    type Error = Infallible;
+
+    fn set_low(&mut self) -> Result<(), Infallible> {
+        Ok(())
+    }
+
+    fn set_high(&mut self) -> Result<(), Infallible> {
+        Ok(())
+    }
+}
+
+fn device(card: MockCard) -> SpiDevice<MockCard, MockPin> {
+    let mut device = SpiDevice::new(card, MockPin);
+    nb::block!(device.init_card()).unwrap();
+    device
+}
+
+fn buffer(count: usize) -> &'static mut [Block] {
+    Box::leak(vec![[0; BLOCK_SIZE]; count].into_boxed_slice())
+}
+
+fn write_and_read_back(device: &mut SpiDevice<MockCard, MockPin>, count: usize, address: u32) {
+    let data = buffer(count);
+    for (i, block) in data.iter_mut().enumerate() {
+        for (j, byte) in block.iter_mut().enumerate() {
+            *byte = (i * 3 + j) as u8;
+        }
+    }
+    let expected = data.to_vec();
+
+    let transfer = device.start_write(&*data, address).map_err(|(e, _)| e);
+    transfer.unwrap().wait().map_err(|(e, _)| e).unwrap();
+    let transfer = device
+        .start_read(buffer(count), address)
+        .map_err(|(e, _)| e);
+    let read = transfer.unwrap().wait().map_err(|(e, _)| e).unwrap();
+    assert_eq!(read.to_vec(), expected);
+}
+
+#[test]
+fn high_capacity_card() {
+    let mut device = device(MockCard::new(false, true));
+    assert_eq!(device.card_size(), Ok(0x3b38 << 10));
+    assert_eq!(device.card_id().unwrap().product_name(), *b"SIMUL");
+
+    write_and_read_back(&mut device, 1, 5);
+    write_and_read_back(&mut device, 3, 10);
+    let (card, _) = device.free();
+    assert!(card.commands.contains(&(17, 5)));
+    assert!(card.commands.contains(&(25, 10)));
+}
+
+#[test]
+fn version1_card() {
+    let mut device = device(MockCard::new(true, false));
+    assert_eq!(device.card_size(), Ok(1024));
+
+    write_and_read_back(&mut device, 1, 2);
+    let (card, _) = device.free();
+    assert!(!card.commands.iter().any(|&(index, _)| index == 58));
+    assert!(card.commands.contains(&(17, 2 * BLOCK_SIZE as u32)));
+}
+
+#[test]
+fn read_crc_error() {
+    let mut device = device(MockCard::new(false, true));
+    device.spi_mut().corrupt_reads = true;
+    let result = device.start_read(buffer(1), 0).map(|_| ());
+    assert_eq!(result.map_err(|(e, _)| e), Err(Error::CRCFail));
+}
+
+#[test]
+fn busy_timeout() {
+    let mut device = device(MockCard::new(false, true));
+    device.spi_mut().stuck_busy = true;
+    // 250 ms at 8 kHz is 250 bytes.
+    device.set_clock(8_000);
+    let transfer = device.start_write(&*buffer(1), 0).map_err(|(e, _)| e);
+    let result = transfer.unwrap().wait().map(|_| ());
+    assert_eq!(result.map_err(|(e, _)| e), Err(Error::Timeout));
+
+    assert_eq!(device.erase(0, 3), Ok(()));
+    let polls = (0..).position(|_| device.result() != Err(nb::Error::WouldBlock));
+    assert_eq!(polls, Some(999));
+    assert_eq!(device.result(), Err(nb::Error::Other(Error::NoOperation)));
+
+    let result = device.start_write(&*buffer(2), 0).map(|_| ());
+    assert_eq!(result.map_err(|(e, _)| e), Err(Error::Timeout));
+}
+
+#[test]
+fn no_card() {
+    let card = MockCard {
+        present: false,
+        ..MockCard::new(false, true)
+    };
+    let mut device = SpiDevice::new(card, MockPin);
+    assert_eq!(nb::block!(device.init_card()), Err(Error::NoCard));
+}